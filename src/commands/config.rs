@@ -0,0 +1,137 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::result::SnekcloudResult;
+use crate::utils::settings::{load_settings_sources, Settings, SettingsSource, ValidateSettings};
+use crate::utils::to_toml_pretty;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::process;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub enum ConfigCommand {
+    /// Validates the configuration and prints all problems
+    Check,
+
+    /// Prints the effective configuration and where each value came from
+    Show(ConfigShowOptions),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct ConfigShowOptions {
+    /// The output format (toml or json)
+    #[structopt(short, long, default_value = "toml")]
+    format: OutputFormat,
+}
+
+#[derive(Debug)]
+enum OutputFormat {
+    Toml,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown format {}", s)),
+        }
+    }
+}
+
+/// Executes the given config command
+pub fn run_config_command(settings: &Settings, command: ConfigCommand) -> SnekcloudResult<()> {
+    match command {
+        ConfigCommand::Check => check_config(settings),
+        ConfigCommand::Show(options) => show_config(settings, &options),
+    }
+}
+
+/// Prints all issues of the settings and exits with a non-zero code if there are any.
+/// The issues aren't returned as an error because main would print them a second time.
+fn check_config(settings: &Settings) -> SnekcloudResult<()> {
+    let issues = settings.validate();
+
+    if issues.is_empty() {
+        println!("The configuration is valid");
+        return Ok(());
+    }
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    process::exit(1)
+}
+
+fn show_config(settings: &Settings, options: &ConfigShowOptions) -> SnekcloudResult<()> {
+    let sources = get_value_sources(settings)?;
+
+    match options.format {
+        OutputFormat::Toml => {
+            for (key, source) in &sources {
+                println!("# {} = {}", key, source);
+            }
+            println!();
+            print!("{}", to_toml_pretty(settings)?);
+        }
+        OutputFormat::Json => {
+            let sources: BTreeMap<String, String> = sources
+                .into_iter()
+                .map(|(key, source)| (key, source.to_string()))
+                .collect();
+            let output = serde_json::json!({
+                "settings": settings,
+                "sources": sources,
+            });
+            println!("{}", serde_json::to_string_pretty(&output)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the source for every value of the settings.
+/// Values that weren't set by any source are using the default.
+fn get_value_sources(settings: &Settings) -> SnekcloudResult<BTreeMap<String, SettingsSource>> {
+    let mut set_sources = load_settings_sources(&settings.config_dir)?;
+    let mut keys = Vec::new();
+    collect_keys(&serde_json::to_value(settings)?, None, &mut keys);
+
+    let sources = keys
+        .into_iter()
+        .map(|key| {
+            let source = set_sources.remove(&key).unwrap_or(SettingsSource::Default);
+            (key, source)
+        })
+        .collect();
+
+    Ok(sources)
+}
+
+/// Collects the dotted keys of all leaf values
+fn collect_keys(value: &Value, prefix: Option<&str>, keys: &mut Vec<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = match prefix {
+                    Some(prefix) => format!("{}.{}", prefix, key),
+                    None => key.clone(),
+                };
+                collect_keys(value, Some(&key), keys);
+            }
+        }
+        _ => {
+            if let Some(prefix) = prefix {
+                keys.push(prefix.to_string())
+            }
+        }
+    }
+}
@@ -0,0 +1,10 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+pub mod config;
+pub mod node;
+pub mod status;
+pub mod trust;
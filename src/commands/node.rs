@@ -0,0 +1,166 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::data::node_data::{node_file_path, NodeData};
+use crate::utils::keys::key_fingerprint;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::settings::Settings;
+use crate::utils::validate_node_id;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub enum NodeCommand {
+    /// Imports a node info file from a path or stdin (-)
+    Add(NodeAddOptions),
+
+    /// Lists all known nodes
+    List,
+
+    /// Shows a single node
+    Show(NodeIdOptions),
+
+    /// Removes a node from the node directory
+    Remove(NodeIdOptions),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct NodeAddOptions {
+    /// The info file to import. Use - to read from stdin
+    #[structopt(parse(from_os_str))]
+    input_file: PathBuf,
+
+    /// Replaces an existing node with a different public key
+    #[structopt(short, long)]
+    force: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct NodeIdOptions {
+    /// The id of the node
+    id: String,
+}
+
+/// Executes the given node command
+pub fn run_node_command(settings: &Settings, command: NodeCommand) -> SnekcloudResult<()> {
+    match command {
+        NodeCommand::Add(options) => add_node(settings, &options),
+        NodeCommand::List => list_nodes(settings),
+        NodeCommand::Show(options) => show_node(settings, &options.id),
+        NodeCommand::Remove(options) => remove_node(settings, &options.id),
+    }
+}
+
+fn add_node(settings: &Settings, options: &NodeAddOptions) -> SnekcloudResult<()> {
+    let data = if options.input_file == Path::new("-") {
+        let mut content = String::new();
+        io::stdin().read_to_string(&mut content)?;
+        NodeData::from_toml(&content)?
+    } else {
+        NodeData::from_file(options.input_file.clone())?
+    };
+    if data.id == settings.node_id {
+        return Err(SnekcloudError::LocalNode(data.id));
+    }
+
+    let existing = find_node(&settings.node_data_dir, &data.id)?;
+    if let Some((path, existing)) = &existing {
+        if existing.public_key() != data.public_key() && !options.force {
+            log::error!("Use --force to replace the node stored in {:?}", path);
+            return Err(SnekcloudError::NodeConflict(data.id));
+        }
+    }
+    if !settings.node_data_dir.exists() {
+        fs::create_dir(&settings.node_data_dir)?;
+    }
+    let path = node_file_path(&settings.node_data_dir, &data.id);
+    data.write_to_file(path.clone())?;
+    // the old file is only removed once the node is stored in its new file
+    if let Some((old_path, _)) = existing {
+        if old_path != path {
+            fs::remove_file(old_path)?;
+        }
+    }
+    log::info!("Added node {} to {:?}", data.id, path);
+    print_node(settings, &data);
+
+    Ok(())
+}
+
+fn list_nodes(settings: &Settings) -> SnekcloudResult<()> {
+    if !settings.node_data_dir.exists() {
+        return Ok(());
+    }
+    println!(
+        "{:<32} {:<7} {:<51} ADDRESSES",
+        "ID", "TRUSTED", "FINGERPRINT"
+    );
+    for (path, data) in NodeData::read_dir(&settings.node_data_dir)? {
+        match data {
+            Ok(data) => println!(
+                "{:<32} {:<7} {:<51} {}",
+                data.id,
+                settings.trusted_nodes.contains(&data.id),
+                key_fingerprint(&data.public_key()),
+                data.addresses.join(", ")
+            ),
+            Err(e) => log::error!("Failed to read node file {:?}: {}", path, e),
+        }
+    }
+
+    Ok(())
+}
+
+fn show_node(settings: &Settings, id: &str) -> SnekcloudResult<()> {
+    let (path, data) = find_node(&settings.node_data_dir, id)?
+        .ok_or_else(|| SnekcloudError::UnknownNode(id.to_string()))?;
+    println!("File:        {:?}", path);
+    print_node(settings, &data);
+
+    Ok(())
+}
+
+fn remove_node(settings: &Settings, id: &str) -> SnekcloudResult<()> {
+    let (path, _) = find_node(&settings.node_data_dir, id)?
+        .ok_or_else(|| SnekcloudError::UnknownNode(id.to_string()))?;
+    fs::remove_file(&path)?;
+    log::info!("Removed node {} ({:?})", id, path);
+    if settings.trusted_nodes.iter().any(|n| n == id) {
+        log::warn!("Node {} is still listed in trusted_nodes", id);
+    }
+
+    Ok(())
+}
+
+/// Searches the node directory for the file containing the node with the given id
+pub fn find_node(dir: &Path, id: &str) -> SnekcloudResult<Option<(PathBuf, NodeData)>> {
+    if !validate_node_id(id) {
+        return Err(SnekcloudError::InvalidNodeId(id.to_string()));
+    }
+    if !dir.exists() {
+        return Ok(None);
+    }
+    let mut found = None;
+
+    for (path, data) in NodeData::read_dir(dir)? {
+        match data {
+            Ok(data) if data.id == id => found = Some((path, data)),
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to read node file {:?}: {}", path, e),
+        }
+    }
+
+    Ok(found)
+}
+
+fn print_node(settings: &Settings, data: &NodeData) {
+    println!("ID:          {}", data.id);
+    println!("Trusted:     {}", settings.trusted_nodes.contains(&data.id));
+    println!("Fingerprint: {}", key_fingerprint(&data.public_key()));
+    println!("Addresses:   {}", data.addresses.join(", "));
+}
@@ -0,0 +1,92 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::control::call;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::settings::Settings;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct PingOptions {
+    /// The id of the node to ping
+    node: String,
+}
+
+/// Prints the cluster membership, heartbeat states and modules of the running server
+pub fn run_status_command(settings: &Settings) -> SnekcloudResult<()> {
+    let socket = control_socket(settings)?;
+    let nodes = call(&socket, "nodes.list", Value::Null)?;
+    let history = match call(&socket, "heartbeat.history", Value::Null) {
+        Err(SnekcloudError::UnknownMethod(_)) => Value::Null,
+        result => result?,
+    };
+    let modules = call(&socket, "modules.list", Value::Null)?;
+
+    println!(
+        "{:<32} {:<8} {:<7} {:<10} {:>8}",
+        "NODE", "STATE", "TRUSTED", "HEARTBEAT", "LATENCY"
+    );
+    for node in nodes.as_array().cloned().unwrap_or_default() {
+        let id = node["id"].as_str().unwrap_or_default();
+        let last_beat = history[id].as_array().and_then(|beats| beats.last());
+        let beat_state = last_beat
+            .and_then(|beat| beat["state"].as_str())
+            .unwrap_or("-");
+        let latency = last_beat
+            .and_then(|beat| beat["ping"].as_u64())
+            .map(|ping| format!("{} ms", ping))
+            .unwrap_or_else(|| "-".to_string());
+
+        println!(
+            "{:<32} {:<8} {:<7} {:<10} {:>8}",
+            id,
+            node["state"].as_str().unwrap_or_default(),
+            node["trusted"].as_bool().unwrap_or_default(),
+            beat_state,
+            latency
+        );
+    }
+    println!();
+    println!(
+        "{:<32} {:<10} {:>8} LAST ERROR",
+        "MODULE", "STATE", "RESTARTS"
+    );
+    for module in modules.as_array().cloned().unwrap_or_default() {
+        println!(
+            "{:<32} {:<10} {:>8} {}",
+            module["name"].as_str().unwrap_or_default(),
+            module["state"].as_str().unwrap_or_default(),
+            module["restarts"].as_u64().unwrap_or_default(),
+            module["last_error"].as_str().unwrap_or("-")
+        );
+    }
+
+    Ok(())
+}
+
+/// Sends a heartbeat to a node via the running server and prints the latency
+pub fn run_ping_command(settings: &Settings, options: PingOptions) -> SnekcloudResult<()> {
+    let socket = control_socket(settings)?;
+    let result = call(&socket, "heartbeat.ping", json!({ "node": options.node }))?;
+    match result["latency_ms"].as_u64() {
+        Some(latency) => println!("Heartbeat to {} answered in {} ms", options.node, latency),
+        None => println!(
+            "Heartbeat to {} was received by an older version without measuring the latency",
+            options.node
+        ),
+    }
+
+    Ok(())
+}
+
+fn control_socket(settings: &Settings) -> SnekcloudResult<PathBuf> {
+    settings
+        .control_socket
+        .clone()
+        .ok_or(SnekcloudError::ControlSocketDisabled)
+}
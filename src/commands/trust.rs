@@ -0,0 +1,104 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::commands::node::find_node;
+use crate::utils::keys::key_fingerprint;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::settings::{write_trusted_nodes, Settings};
+use crate::utils::validate_node_id;
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub struct TrustOptions {
+    /// The id of the node that should be trusted
+    id: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<TrustCommand>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum TrustCommand {
+    /// Lists all trusted nodes
+    List,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct UntrustOptions {
+    /// The id of the node that should no longer be trusted
+    id: String,
+}
+
+/// Executes the trust command
+pub fn run_trust_command(settings: &Settings, options: TrustOptions) -> SnekcloudResult<()> {
+    match (options.command, options.id) {
+        (None, Some(id)) => trust_node(settings, id),
+        _ => list_trusted(settings),
+    }
+}
+
+/// Executes the untrust command
+pub fn run_untrust_command(settings: &Settings, options: UntrustOptions) -> SnekcloudResult<()> {
+    let id = options.id;
+    if !settings.trusted_nodes.contains(&id) {
+        log::warn!("Node {} is not trusted", id);
+        return Ok(());
+    }
+    let trusted_nodes = settings
+        .trusted_nodes
+        .iter()
+        .filter(|n| **n != id)
+        .cloned()
+        .collect();
+    let path = write_trusted_nodes(&settings.config_dir, trusted_nodes)?;
+    log::info!("Node {} is no longer trusted ({:?})", id, path);
+
+    Ok(())
+}
+
+fn trust_node(settings: &Settings, id: String) -> SnekcloudResult<()> {
+    if !validate_node_id(&id) {
+        return Err(SnekcloudError::InvalidNodeId(id));
+    }
+    print_trusted_node(settings, &id)?;
+
+    if settings.trusted_nodes.contains(&id) {
+        log::info!("Node {} is already trusted", id);
+        return Ok(());
+    }
+    let mut trusted_nodes = settings.trusted_nodes.clone();
+    trusted_nodes.push(id.clone());
+    let path = write_trusted_nodes(&settings.config_dir, trusted_nodes)?;
+    log::info!("Node {} is now trusted ({:?})", id, path);
+
+    Ok(())
+}
+
+fn list_trusted(settings: &Settings) -> SnekcloudResult<()> {
+    for id in &settings.trusted_nodes {
+        print_trusted_node(settings, id)?;
+    }
+
+    Ok(())
+}
+
+/// Prints the trusted id with the fingerprint of the known public key
+/// and warns if there is no node data for the id
+fn print_trusted_node(settings: &Settings, id: &str) -> SnekcloudResult<()> {
+    match find_node(&settings.node_data_dir, id)? {
+        Some((_, data)) => println!("{:<32} {}", id, key_fingerprint(&data.public_key())),
+        None => {
+            println!("{:<32} unknown", id);
+            log::warn!(
+                "There is no node data for {} in {:?}",
+                id,
+                settings.node_data_dir
+            );
+        }
+    }
+
+    Ok(())
+}
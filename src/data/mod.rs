@@ -5,3 +5,4 @@
  */
 
 pub mod node_data;
+pub mod storage;
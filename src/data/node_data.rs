@@ -5,14 +5,14 @@
  */
 
 use crate::utils::keys::{armor_public_key, extract_public_key};
-use crate::utils::result::SnekcloudResult;
-use crate::utils::write_toml_pretty;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::{validate_node_id, write_toml_pretty};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use vented::stream::PublicKey;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct NodeData {
     pub id: String,
     pub addresses: Vec<String>,
@@ -32,17 +32,91 @@ impl NodeData {
     /// Creates the data structure from a given file
     pub fn from_file(path: PathBuf) -> SnekcloudResult<Self> {
         let content = fs::read_to_string(path)?;
-        let result = toml::from_str(&content)?;
+
+        Self::from_toml(&content)
+    }
+
+    /// Parses and validates the data from a toml string
+    pub fn from_toml(content: &str) -> SnekcloudResult<Self> {
+        let result: Self = toml::from_str(content)?;
+        result.validate()?;
 
         Ok(result)
     }
 
+    /// Reads all node data files in the given directory.
+    /// The local node file is skipped and files that fail to parse
+    /// are returned with their error.
+    pub fn read_dir(path: &Path) -> SnekcloudResult<Vec<(PathBuf, SnekcloudResult<Self>)>> {
+        let entries = glob::glob(format!("{}/*.toml", path.to_string_lossy()).as_str())?
+            .filter_map(|path| path.ok())
+            .filter(|path| {
+                !path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().eq_ignore_ascii_case("local"))
+                    .unwrap_or(false)
+            })
+            .map(|path| {
+                let data = Self::from_file(path.clone());
+                (path, data)
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
     /// Writes the data to the given file
     pub fn write_to_file(&self, path: PathBuf) -> SnekcloudResult<()> {
         write_toml_pretty(&path, self)
     }
 
+    /// Checks that the node id is valid and the public key can be decoded
+    pub fn validate(&self) -> SnekcloudResult<()> {
+        if !validate_node_id(&self.id) {
+            return Err(SnekcloudError::InvalidNodeId(self.id.clone()));
+        }
+        extract_public_key(&self.public_key)?;
+
+        Ok(())
+    }
+
     pub fn public_key(&self) -> PublicKey {
         extract_public_key(&self.public_key).unwrap()
     }
 }
+
+/// Returns the path of the file the node with the given id is stored in.
+/// All characters except ascii letters, digits, `-`, `_`, `+` and `=` are percent encoded
+/// so that ids like `../x` or the `/` of base64 ids can't point outside of the directory.
+pub fn node_file_path(dir: &Path, id: &str) -> PathBuf {
+    let mut file_name = String::with_capacity(id.len() + 5);
+    for byte in id.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_+=".contains(&byte) {
+            file_name.push(byte as char);
+        } else {
+            file_name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    file_name.push_str(".toml");
+
+    dir.join(file_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_node_files_inside_the_directory() {
+        let dir = Path::new("nodes");
+
+        assert_eq!(node_file_path(dir, "node-1"), dir.join("node-1.toml"));
+        assert_eq!(node_file_path(dir, "ab+c/d=="), dir.join("ab+c%2Fd==.toml"));
+        assert_eq!(
+            node_file_path(dir, "../../x"),
+            dir.join("%2E%2E%2F%2E%2E%2Fx.toml")
+        );
+        assert_eq!(node_file_path(dir, "a\\b"), dir.join("a%5Cb.toml"));
+        assert_eq!(node_file_path(dir, "..").parent(), Some(dir));
+    }
+}
@@ -0,0 +1,267 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use parking_lot::Mutex;
+use regex::Regex;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The placeholder in migrations that gets replaced with the namespace of the module
+const NAMESPACE_PLACEHOLDER: &str = "{namespace}";
+
+/// The SQLite database shared by all modules
+#[derive(Clone)]
+pub struct Storage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+/// The part of the storage that belongs to a single module.
+/// Keys and tables of different namespaces don't interfere with each other.
+#[derive(Clone)]
+pub struct ModuleStorage {
+    namespace: String,
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Storage {
+    /// Opens or creates the database at the given path
+    pub fn open(path: &Path) -> SnekcloudResult<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS migrations (
+                namespace TEXT PRIMARY KEY,
+                version INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS kv (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            );",
+        )?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Returns the storage for the given namespace.
+    /// The namespace is used as table prefix and may only contain
+    /// lowercase letters and digits so that the tables of two namespaces can't collide.
+    pub fn namespace(&self, namespace: &str) -> SnekcloudResult<ModuleStorage> {
+        lazy_static! {
+            static ref NAMESPACE_REGEX: Regex =
+                Regex::new(r"^[a-z][a-z0-9]*$").expect("Failed to compile regex");
+        }
+        if !NAMESPACE_REGEX.is_match(namespace) {
+            return Err(SnekcloudError::InvalidNamespace(namespace.to_string()));
+        }
+
+        Ok(ModuleStorage {
+            namespace: namespace.to_string(),
+            connection: Arc::clone(&self.connection),
+        })
+    }
+}
+
+impl ModuleStorage {
+    /// Applies all migrations that haven't been applied to the namespace yet.
+    /// Migrations are identified by their position so existing ones must never be changed
+    /// or reordered. Occurrences of `{namespace}` are replaced with the namespace.
+    pub fn migrate(&self, migrations: &[&str]) -> SnekcloudResult<()> {
+        self.transaction(|transaction| {
+            let version: usize = transaction
+                .query_row(
+                    "SELECT version FROM migrations WHERE namespace = ?1",
+                    params![self.namespace],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+                .unwrap_or(0) as usize;
+
+            for (index, migration) in migrations.iter().enumerate().skip(version) {
+                log::debug!("Applying migration {} of {}", index + 1, self.namespace);
+                transaction
+                    .execute_batch(&migration.replace(NAMESPACE_PLACEHOLDER, &self.namespace))?;
+            }
+            if migrations.len() > version {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO migrations (namespace, version) VALUES (?1, ?2)",
+                    params![self.namespace, migrations.len() as i64],
+                )?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Returns the name of the table of the module with the given name.
+    /// The name may only contain lowercase letters, digits and underscores.
+    pub fn table(&self, name: &str) -> SnekcloudResult<String> {
+        lazy_static! {
+            static ref TABLE_REGEX: Regex =
+                Regex::new(r"^[a-z][a-z0-9_]*$").expect("Failed to compile regex");
+        }
+        if !TABLE_REGEX.is_match(name) {
+            return Err(SnekcloudError::InvalidTableName(name.to_string()));
+        }
+
+        Ok(format!("{}_{}", self.namespace, name))
+    }
+
+    /// Runs the function in a transaction that is committed when the function succeeds
+    pub fn transaction<F, R>(&self, func: F) -> SnekcloudResult<R>
+    where
+        F: FnOnce(&Transaction) -> rusqlite::Result<R>,
+    {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction()?;
+        let result = func(&transaction)?;
+        transaction.commit()?;
+
+        Ok(result)
+    }
+
+    /// Returns the value stored with the given key
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> SnekcloudResult<Option<T>> {
+        let value: Option<String> = self
+            .connection
+            .lock()
+            .query_row(
+                "SELECT value FROM kv WHERE namespace = ?1 AND key = ?2",
+                params![self.namespace, key],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Stores the value with the given key and replaces an existing value
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> SnekcloudResult<()> {
+        let value = serde_json::to_string(value)?;
+        self.connection.lock().execute(
+            "INSERT OR REPLACE INTO kv (namespace, key, value) VALUES (?1, ?2, ?3)",
+            params![self.namespace, key, value],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::NO_PARAMS;
+
+    const MIGRATIONS: &[&str] = &[
+        "CREATE TABLE {namespace}_items (name TEXT NOT NULL)",
+        "ALTER TABLE {namespace}_items ADD COLUMN amount INTEGER",
+    ];
+
+    fn storage() -> Storage {
+        Storage::open(Path::new(":memory:")).unwrap()
+    }
+
+    fn count_items(storage: &ModuleStorage) -> i64 {
+        let table = storage.table("items").unwrap();
+        storage
+            .transaction(|transaction| {
+                transaction.query_row(
+                    &format!("SELECT COUNT(*) FROM {}", table),
+                    NO_PARAMS,
+                    |row| row.get(0),
+                )
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn it_rejects_invalid_namespaces() {
+        let storage = storage();
+
+        assert!(storage.namespace("module1").is_ok());
+        assert!(storage.namespace("module_1").is_err());
+        assert!(storage.namespace("Module").is_err());
+        assert!(storage.namespace("1module").is_err());
+        assert!(storage.namespace("a; DROP TABLE kv").is_err());
+    }
+
+    #[test]
+    fn it_rejects_invalid_table_names() {
+        let storage = storage().namespace("module").unwrap();
+
+        assert_eq!(storage.table("items_v2").unwrap(), "module_items_v2");
+        assert!(storage.table("").is_err());
+        assert!(storage.table("_items").is_err());
+        assert!(storage.table("items; DROP TABLE kv").is_err());
+    }
+
+    #[test]
+    fn it_applies_each_migration_once() {
+        let storage = storage().namespace("module").unwrap();
+        storage.migrate(&MIGRATIONS[..1]).unwrap();
+        storage
+            .transaction(|transaction| {
+                transaction.execute("INSERT INTO module_items (name) VALUES ('a')", NO_PARAMS)
+            })
+            .unwrap();
+
+        storage.migrate(MIGRATIONS).unwrap();
+        storage.migrate(MIGRATIONS).unwrap();
+        storage
+            .transaction(|transaction| {
+                transaction.execute(
+                    "INSERT INTO module_items (name, amount) VALUES ('b', 2)",
+                    NO_PARAMS,
+                )
+            })
+            .unwrap();
+        assert_eq!(count_items(&storage), 2);
+    }
+
+    #[test]
+    fn it_separates_namespaces() {
+        let storage = storage();
+        let first = storage.namespace("first").unwrap();
+        let second = storage.namespace("second").unwrap();
+        first.migrate(MIGRATIONS).unwrap();
+        second.migrate(&MIGRATIONS[..1]).unwrap();
+
+        first.set("key", &1).unwrap();
+        second.set("key", &"value").unwrap();
+        first
+            .transaction(|transaction| {
+                transaction.execute("INSERT INTO first_items (name) VALUES ('a')", NO_PARAMS)
+            })
+            .unwrap();
+
+        assert_eq!(first.get::<i32>("key").unwrap(), Some(1));
+        assert_eq!(
+            second.get::<String>("key").unwrap(),
+            Some("value".to_string())
+        );
+        assert_eq!(first.get::<i32>("missing").unwrap(), None);
+        assert_eq!(count_items(&first), 1);
+        assert_eq!(count_items(&second), 0);
+    }
+
+    #[test]
+    fn it_replaces_stored_values() {
+        let storage = storage().namespace("module").unwrap();
+
+        storage.set("key", &vec![1, 2]).unwrap();
+        storage.set("key", &vec![3]).unwrap();
+        assert_eq!(storage.get::<Vec<i32>>("key").unwrap(), Some(vec![3]));
+    }
+}
@@ -4,16 +4,21 @@
  * See LICENSE for more information
  */
 
+use crate::commands::config::{run_config_command, ConfigCommand};
+use crate::commands::node::{run_node_command, NodeCommand};
+use crate::commands::status::{run_ping_command, run_status_command, PingOptions};
+use crate::commands::trust::{
+    run_trust_command, run_untrust_command, TrustOptions, UntrustOptions,
+};
 use crate::data::node_data::NodeData;
-use crate::modules::heartbeat::HeartbeatModule;
-use crate::modules::nodes_refresh::NodesRefreshModule;
+use crate::modules::registry::ModuleRegistry;
 use crate::server::SnekcloudServer;
 use crate::utils::keys::{
     armor_private_key, extract_private_key, generate_private_key, read_node_keys,
 };
 use crate::utils::logging::init_logger;
 use crate::utils::result::SnekcloudResult;
-use crate::utils::settings::{get_settings, Settings, ValidateSettings};
+use crate::utils::settings::{load_settings, Settings, CONFIG_DIR_ENV, DEFAULT_CONFIG_DIR};
 use std::fs;
 use std::path::PathBuf;
 use structopt::StructOpt;
@@ -22,13 +27,27 @@ use vented::stream::SecretKey;
 #[macro_use]
 extern crate lazy_static;
 
+pub(crate) mod commands;
 pub(crate) mod data;
 pub(crate) mod modules;
 pub(crate) mod server;
+#[cfg(test)]
+pub(crate) mod testing;
 pub(crate) mod utils;
 
 #[derive(StructOpt, Debug)]
 struct Opt {
+    /// The directory the configuration is loaded from.
+    /// Relative paths in the configuration are resolved against it
+    #[structopt(
+        long,
+        global = true,
+        env = CONFIG_DIR_ENV,
+        default_value = DEFAULT_CONFIG_DIR,
+        parse(from_os_str)
+    )]
+    config_dir: PathBuf,
+
     #[structopt(subcommand)]
     sub_command: Option<SubCommand>,
 }
@@ -40,6 +59,24 @@ enum SubCommand {
     GenerateKey(GenerateKeyOptions),
 
     WriteInfoFile(WriteInfoFileOptions),
+
+    /// Manages the nodes stored in the node data directory
+    Node(NodeCommand),
+
+    /// Inspects the configuration
+    Config(ConfigCommand),
+
+    /// Prints the status of the running server
+    Status,
+
+    /// Sends a heartbeat to a node via the running server
+    Ping(PingOptions),
+
+    /// Trusts a node or lists the trusted nodes
+    Trust(TrustOptions),
+
+    /// Removes a node from the trusted nodes
+    Untrust(UntrustOptions),
 }
 
 #[derive(StructOpt, Debug)]
@@ -57,14 +94,27 @@ struct WriteInfoFileOptions {
 }
 
 fn main() -> SnekcloudResult<()> {
-    init_logger();
     let opt: Opt = Opt::from_args();
-    let settings = get_settings();
+    let settings = load_settings(&opt.config_dir)?;
+    init_logger(&settings.log_folder);
+    for path in &settings.legacy_paths {
+        log::warn!(
+            "Using {:?} relative to the working directory. Move it into {:?} to resolve it against the config directory",
+            path,
+            settings.config_dir
+        );
+    }
 
     if let Some(command) = opt.sub_command {
         match command {
             SubCommand::GenerateKey(options) => generate_key(&options.output_file)?,
             SubCommand::WriteInfoFile(options) => write_info_file(&settings, &options.output_file)?,
+            SubCommand::Node(command) => run_node_command(&settings, command)?,
+            SubCommand::Config(command) => run_config_command(&settings, command)?,
+            SubCommand::Status => run_status_command(&settings)?,
+            SubCommand::Ping(options) => run_ping_command(&settings, options)?,
+            SubCommand::Trust(options) => run_trust_command(&settings, options)?,
+            SubCommand::Untrust(options) => run_untrust_command(&settings, options)?,
         }
     } else {
         start_server(opt, &settings)?;
@@ -84,8 +134,8 @@ fn generate_key(output_file: &PathBuf) -> SnekcloudResult<()> {
 }
 
 fn write_info_file(settings: &Settings, output_file: &PathBuf) -> SnekcloudResult<()> {
-    settings.validate();
-    let key = get_private_key(&settings)?;
+    settings.check()?;
+    let key = get_private_key(settings)?;
     let data = NodeData::with_addresses(
         settings.node_id.clone(),
         settings.listen_addresses.clone(),
@@ -100,26 +150,34 @@ fn write_info_file(settings: &Settings, output_file: &PathBuf) -> SnekcloudResul
 
 fn start_server(_options: Opt, settings: &Settings) -> SnekcloudResult<()> {
     if !settings.private_key.exists() {
+        log::warn!(
+            "No private key found at {:?}. The node starts with a new identity",
+            settings.private_key
+        );
         generate_key(&settings.private_key)?;
     }
-    settings.validate();
-    let keys = read_node_keys(&settings.node_data_dir)?;
+    settings.check()?;
+    let keys = read_node_keys(settings)?;
     let private_key = get_private_key(settings)?;
     write_info_file(
-        &settings,
+        settings,
         &settings
             .node_data_dir
             .clone()
             .join(PathBuf::from("local.toml")),
     )?;
 
-    let mut server = SnekcloudServer::new(settings.node_id.clone(), private_key, keys);
+    let mut server = SnekcloudServer::new(settings.clone(), private_key, keys)?;
 
     for address in &settings.listen_addresses {
         server.add_listen_address(address.clone());
     }
-    server.register_module(HeartbeatModule::new())?;
-    server.register_module(NodesRefreshModule::new())?;
+    if let Some(path) = &settings.control_socket {
+        server.set_control_socket(path.clone());
+    }
+    for module in ModuleRegistry::default().build(settings) {
+        server.register_module(module)?;
+    }
     server.run()?;
 
     Ok(())
@@ -4,27 +4,52 @@
  * See LICENSE for more information
  */
 
+use crate::data::storage::ModuleStorage;
 use crate::modules::heartbeat::payloads::HeartbeatPayload;
 use crate::modules::heartbeat::settings::HeartbeatSettings;
+use crate::modules::topics::{
+    NodeLiveness, RemovalReason, NODE_DISCOVERED, NODE_LIVENESS, NODE_REMOVED,
+};
 use crate::modules::Module;
+use crate::server::events::{EventRegistry, TypedEvent, TypedRequest};
 use crate::server::tick_context::RunContext;
-use crate::utils::result::SnekcloudResult;
-use crate::utils::settings::get_settings;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::settings::{RestartSettings, Settings};
 use crate::utils::write_json_pretty;
-use async_std::task;
 use async_trait::async_trait;
 use chrono::Local;
+use futures::future::{self, join, select, BoxFuture, Either};
+use futures::pin_mut;
+use futures::stream::{FuturesUnordered, StreamExt};
 use parking_lot::Mutex;
+use rusqlite::{params, NO_PARAMS};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use vented::event::Event;
-use vented::server::VentedServer;
+use std::time::Duration;
 
 mod payloads;
 pub mod settings;
-const HEARTBEAT_BEAT_EVENT: &str = "heartbeat:beat";
+#[cfg(test)]
+mod tests;
+const HEARTBEAT_ECHO: TypedRequest<HeartbeatPayload, HeartbeatPayload> =
+    TypedRequest::new("heartbeat:echo");
+/// The one-way beat sent by older nodes.
+/// It keeps its own name so that older nodes never receive request envelopes they can't parse.
+/// Nodes that don't answer echo requests receive it instead.
+const HEARTBEAT_LEGACY_BEAT: TypedEvent<HeartbeatPayload> = TypedEvent::new("heartbeat:beat");
+const HEARTBEAT_HISTORY_METHOD: &str = "heartbeat.history";
+const HEARTBEAT_PING_METHOD: &str = "heartbeat.ping";
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+const HEARTBEAT_STORAGE_NAMESPACE: &str = "heartbeat";
+const HEARTBEAT_MIGRATIONS: &[&str] = &["CREATE TABLE {namespace}_records (
+    node TEXT NOT NULL,
+    ping INTEGER,
+    alive INTEGER NOT NULL,
+    timestamp TEXT NOT NULL
+)"];
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum NodeState {
@@ -47,6 +72,15 @@ impl NodeInfo {
             timestamp: Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
         }
     }
+    /// The node received a one-way beat which doesn't measure the latency
+    fn reachable() -> Self {
+        Self {
+            ping: None,
+            state: NodeState::Alive,
+            timestamp: Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
+        }
+    }
+
     fn dead() -> Self {
         Self {
             ping: None,
@@ -54,18 +88,39 @@ impl NodeInfo {
             timestamp: Local::now().format("%Y-%m-%dT%H:%M:%S").to_string(),
         }
     }
+
+    fn is_alive(&self) -> bool {
+        matches!(self.state, NodeState::Alive)
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryParams {
+    #[serde(default)]
+    node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PingParams {
+    node: String,
 }
 
 pub struct HeartbeatModule {
     settings: HeartbeatSettings,
     node_states: Arc<Mutex<HashMap<String, Vec<NodeInfo>>>>,
+    max_records: Arc<AtomicUsize>,
+    storage: Option<ModuleStorage>,
 }
 
 impl HeartbeatModule {
-    pub fn new() -> Self {
+    pub fn new(settings: &Settings) -> Self {
         Self {
-            settings: get_settings().modules.heartbeat,
+            settings: settings.modules.heartbeat.clone(),
             node_states: Arc::new(Mutex::new(HashMap::new())),
+            max_records: Arc::new(AtomicUsize::new(
+                settings.modules.heartbeat.max_record_history,
+            )),
+            storage: None,
         }
     }
 }
@@ -76,28 +131,44 @@ impl Module for HeartbeatModule {
         "HeartbeatModule".to_string()
     }
 
-    fn init(&mut self, server: &mut VentedServer) -> SnekcloudResult<()> {
-        server.on(HEARTBEAT_BEAT_EVENT, {
+    fn init(&mut self, events: &mut EventRegistry) -> SnekcloudResult<()> {
+        events.on_typed(&HEARTBEAT_LEGACY_BEAT, {
             let node_states = Arc::clone(&self.node_states);
-
-            move |event| {
-                let node_states = Arc::clone(&node_states);
-                Box::pin(async move {
-                    let payload = event.get_payload::<HeartbeatPayload>().unwrap();
-                    let latency = payload.get_beat_time().elapsed().ok()?.as_millis();
-                    log::debug!("Latency to node {} is {} ms", payload.node_id, latency);
-
-                    let mut states = node_states.lock();
-                    Self::insert_state(
-                        &mut states,
-                        payload.node_id,
-                        NodeInfo::alive(latency as u64),
-                    );
-
-                    None
-                })
+            let max_records = Arc::clone(&self.max_records);
+            let bus = events.bus();
+            move |origin, payload: HeartbeatPayload| {
+                let node = origin.unwrap_or(payload.node_id);
+                log::debug!(
+                    "Received heartbeat from {} which runs an older version",
+                    node
+                );
+                // the beat only travels one way so the latency can't be measured
+                let change = Self::record_state(
+                    &node_states,
+                    &node,
+                    NodeInfo::reachable(),
+                    max_records.load(Ordering::Relaxed),
+                );
+                if let Some(change) = change {
+                    bus.publish(&NODE_LIVENESS, change);
+                }
+                async { None }
             }
         });
+        events.on_request(
+            &HEARTBEAT_ECHO,
+            |origin, payload: HeartbeatPayload| async move {
+                if let Ok(elapsed) = payload.get_beat_time().elapsed() {
+                    log::trace!(
+                        "Received heartbeat from {} sent {} ms ago",
+                        origin.as_ref().unwrap_or(&payload.node_id),
+                        elapsed.as_millis()
+                    );
+                }
+                // the beat is echoed so that the sender can measure the round trip time
+                Ok(payload)
+            },
+        );
 
         Ok(())
     }
@@ -106,49 +177,267 @@ impl Module for HeartbeatModule {
         Box::new(self)
     }
 
+    fn restart_settings(&self) -> RestartSettings {
+        self.settings.restart.clone()
+    }
+
+    fn reconfigure(&mut self, settings: &Settings) -> SnekcloudResult<()> {
+        self.settings = settings.modules.heartbeat.clone();
+        self.max_records
+            .store(self.settings.max_record_history, Ordering::Relaxed);
+
+        Ok(())
+    }
+
     async fn run(&mut self, context: RunContext) -> SnekcloudResult<()> {
-        for node in context.nodes() {
-            let mut context = context.clone();
+        if self.storage.is_none() {
+            let storage = context.storage(HEARTBEAT_STORAGE_NAMESPACE)?;
+            storage.migrate(HEARTBEAT_MIGRATIONS)?;
+            self.load_states(&storage)?;
+            self.storage = Some(storage);
+        }
+        context.register_control_method(HEARTBEAT_HISTORY_METHOD, {
+            let node_states = Arc::clone(&self.node_states);
+            move |params| {
+                let node_states = Arc::clone(&node_states);
+                async move {
+                    let params: HistoryParams = serde_json::from_value(params)?;
+                    let states = node_states.lock();
+
+                    match params.node {
+                        Some(node) => {
+                            let history =
+                                states.get(&node).ok_or(SnekcloudError::UnknownNode(node))?;
+                            Ok(serde_json::to_value(history)?)
+                        }
+                        None => Ok(serde_json::to_value(&*states)?),
+                    }
+                }
+            }
+        });
+
+        context.register_control_method(HEARTBEAT_PING_METHOD, {
             let node_states = Arc::clone(&self.node_states);
-            let interval = self.settings.interval();
+            let max_records = Arc::clone(&self.max_records);
+            let context = context.clone();
+            move |params| {
+                let node_states = Arc::clone(&node_states);
+                let max_records = max_records.load(Ordering::Relaxed);
+                let mut context = context.clone();
+                async move {
+                    let params: PingParams = serde_json::from_value(params)?;
+                    let latency =
+                        Self::send_heartbeat(&mut context, &params.node, node_states, max_records)
+                            .await?;
+
+                    // older nodes only receive a one-way beat without a latency
+                    Ok(json!({
+                        "node": params.node,
+                        "latency_ms": latency.map(|latency| latency.as_millis() as u64),
+                    }))
+                }
+            }
+        });
+
+        let discovered = context.subscribe(&NODE_DISCOVERED);
+        let beating = Arc::new(Mutex::new(HashSet::new()));
+        let heartbeats = async {
+            let mut heartbeats: FuturesUnordered<BoxFuture<()>> = FuturesUnordered::new();
+            for node in context.nodes() {
+                beating.lock().insert(node.id.clone());
+                heartbeats.push(self.beat_node(context.clone(), node.id, Arc::clone(&beating)));
+            }
+            // keeps the set of heartbeats from completing when there are no nodes
+            heartbeats.push(Box::pin(future::pending()));
+
+            loop {
+                let next_discovery = discovered.recv();
+                pin_mut!(next_discovery);
 
-            task::spawn(async move {
+                match select(heartbeats.next(), next_discovery).await {
+                    Either::Right((Ok(discovery), _)) => {
+                        // the previous loop keeps running when a node is removed and added again
+                        if beating.lock().insert(discovery.node.clone()) {
+                            log::debug!("Starting heartbeats to new node {}", discovery.node);
+                            heartbeats.push(self.beat_node(
+                                context.clone(),
+                                discovery.node,
+                                Arc::clone(&beating),
+                            ));
+                        }
+                    }
+                    Either::Right((Err(_), _)) => break,
+                    Either::Left(_) => {}
+                }
+            }
+            heartbeats.for_each(|_| async {}).await
+        };
+        let output = async {
+            loop {
+                self.persist_states();
+                context.clock().sleep(self.settings.interval()).await
+            }
+        };
+        join(heartbeats, output).await;
+
+        Ok(())
+    }
+
+    async fn shutdown(&mut self, _context: RunContext) -> SnekcloudResult<()> {
+        self.persist_states();
+
+        Ok(())
+    }
+}
+
+impl HeartbeatModule {
+    /// Returns the loop that periodically sends heartbeats to the node
+    /// until its removal is published
+    fn beat_node(
+        &self,
+        context: RunContext,
+        node: String,
+        beating: Arc<Mutex<HashSet<String>>>,
+    ) -> BoxFuture<'static, ()> {
+        let node_states = Arc::clone(&self.node_states);
+        let interval = self.settings.interval();
+        let max_records = Arc::clone(&self.max_records);
+        // subscribing before the loop starts keeps removals in between from being missed
+        let removals = context.subscribe(&NODE_REMOVED);
+
+        Box::pin(async move {
+            let removed = async {
+                if !context.has_node(&node) {
+                    return;
+                }
+                while let Ok(removal) = removals.recv().await {
+                    // the node can be added again before the removal is received
+                    if removal.node == node
+                        && removal.reason == RemovalReason::Deleted
+                        && !context.has_node(&node)
+                    {
+                        break;
+                    }
+                }
+            };
+            let beats = async {
+                let mut context = context.clone();
                 loop {
-                    Self::send_heartbeat(&mut context, &node.id, Arc::clone(&node_states)).await;
+                    let _ = Self::send_heartbeat(
+                        &mut context,
+                        &node,
+                        Arc::clone(&node_states),
+                        max_records.load(Ordering::Relaxed),
+                    )
+                    .await;
 
-                    if !context.check_alive(&node.id) {
-                        let start = Instant::now();
-                        while !context.check_alive(&node.id) {
-                            task::sleep(Duration::from_secs(10)).await;
-                            if start.elapsed() > interval * 100 {
+                    if !context.check_alive(&node) {
+                        let start = context.clock().now();
+                        while !context.check_alive(&node) {
+                            context.clock().sleep(Duration::from_secs(10)).await;
+                            if context.clock().elapsed(start) > interval * 100 {
                                 break;
                             }
                         }
                     } else {
-                        task::sleep(interval).await
+                        context.clock().sleep(interval).await
                     }
                 }
-            });
+            };
+            pin_mut!(removed, beats);
+            select(removed, beats).await;
+
+            log::debug!("Stopping heartbeats to removed node {}", node);
+            beating.lock().remove(&node);
+        })
+    }
+
+    /// Writes the recorded states to the storage
+    /// and to the output file if one is configured
+    fn persist_states(&self) {
+        if let Some(path) = &self.settings.output_file {
+            let states = self.node_states.lock();
+            if let Err(e) = write_json_pretty(path, &*states) {
+                log::error!("Failed to write output states to file: {}", e)
+            }
         }
-        loop {
-            if let Some(path) = &self.settings.output_file {
-                let states = self.node_states.lock();
-                if let Err(e) = write_json_pretty(path, &*states) {
-                    log::error!("Failed to write output states to file: {}", e)
-                }
+        if let Some(storage) = &self.storage {
+            if let Err(e) = self.save_states(storage) {
+                log::error!("Failed to store heartbeat states: {}", e)
             }
-            task::sleep(self.settings.interval()).await
         }
     }
-}
 
-impl HeartbeatModule {
-    fn insert_state(states: &mut HashMap<String, Vec<NodeInfo>>, id: String, state: NodeInfo) {
-        lazy_static! {
-            static ref MAX_RECORDS: usize = get_settings().modules.heartbeat.max_record_history;
+    /// Replaces the stored states with the recorded ones
+    fn save_states(&self, storage: &ModuleStorage) -> SnekcloudResult<()> {
+        let table = storage.table("records")?;
+        let states = self.node_states.lock().clone();
+
+        storage.transaction(|transaction| {
+            transaction.execute(&format!("DELETE FROM {}", table), NO_PARAMS)?;
+            let mut statement = transaction.prepare(&format!(
+                "INSERT INTO {} (node, ping, alive, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                table
+            ))?;
+            for (node, infos) in &states {
+                for info in infos {
+                    statement.execute(params![
+                        node,
+                        info.ping.map(|ping| ping as i64),
+                        info.is_alive(),
+                        info.timestamp
+                    ])?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Loads the states that were stored by a previous run
+    fn load_states(&self, storage: &ModuleStorage) -> SnekcloudResult<()> {
+        let table = storage.table("records")?;
+        let records = storage.transaction(|transaction| {
+            let mut statement = transaction.prepare(&format!(
+                "SELECT node, ping, alive, timestamp FROM {} ORDER BY rowid",
+                table
+            ))?;
+            let records = statement
+                .query_map(NO_PARAMS, |row| {
+                    let alive: bool = row.get(2)?;
+                    let info = NodeInfo {
+                        ping: row.get::<_, Option<i64>>(1)?.map(|ping| ping as u64),
+                        state: if alive {
+                            NodeState::Alive
+                        } else {
+                            NodeState::Dead
+                        },
+                        timestamp: row.get(3)?,
+                    };
+                    Ok((row.get::<_, String>(0)?, info))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            Ok(records)
+        })?;
+        log::debug!("Loaded {} stored heartbeat records", records.len());
+
+        let mut states = self.node_states.lock();
+        for (node, info) in records {
+            Self::insert_state(&mut states, node, info, self.settings.max_record_history);
         }
+
+        Ok(())
+    }
+
+    fn insert_state(
+        states: &mut HashMap<String, Vec<NodeInfo>>,
+        id: String,
+        state: NodeInfo,
+        max_records: usize,
+    ) {
         if let Some(states) = states.get_mut(&id) {
-            if states.len() > *MAX_RECORDS {
+            while states.len() > max_records {
                 states.remove(0);
             }
             states.push(state);
@@ -157,35 +446,83 @@ impl HeartbeatModule {
         }
     }
 
+    /// Records the state of the node and returns the change of its liveness if there is one
+    fn record_state(
+        states: &Mutex<HashMap<String, Vec<NodeInfo>>>,
+        node: &str,
+        info: NodeInfo,
+        max_records: usize,
+    ) -> Option<NodeLiveness> {
+        let alive = info.is_alive();
+        let mut states = states.lock();
+        let was_alive = states
+            .get(node)
+            .and_then(|infos| infos.last())
+            .map(NodeInfo::is_alive);
+        Self::insert_state(&mut states, node.to_string(), info, max_records);
+
+        // the first beat to a node isn't a change so that starting the module
+        // doesn't announce every node as revived
+        match was_alive {
+            Some(was_alive) if was_alive != alive => Some(NodeLiveness {
+                node: node.to_string(),
+                alive,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Sends a heartbeat to the target and returns the round trip time.
+    /// Nodes that don't answer the request receive a one-way beat without a latency instead.
     async fn send_heartbeat(
         context: &mut RunContext,
         target: &String,
         states: Arc<Mutex<HashMap<String, Vec<NodeInfo>>>>,
-    ) {
+        max_records: usize,
+    ) -> SnekcloudResult<Option<Duration>> {
         log::trace!("Sending heartbeat to {}...", target);
-        let mut value = context
-            .emit(
-                target.clone(),
-                Event::with_payload(
-                    HEARTBEAT_BEAT_EVENT,
-                    &HeartbeatPayload::now(context.node_id().clone()),
-                ),
-            )
-            .await;
-
-        match value
-            .get_value_with_timeout_async(Duration::from_secs(60))
+        let start = context.clock().now();
+        let payload = HeartbeatPayload::now(context.node_id().clone());
+        let mut result = context
+            .request(target, &HEARTBEAT_ECHO, &payload, HEARTBEAT_TIMEOUT)
             .await
-        {
-            Some(Err(e)) => {
-                log::debug!("Node {} is not reachable: {}", target, e);
-                Self::insert_state(&mut *states.lock(), target.clone(), NodeInfo::dead());
+            .map(|_| Some(context.clock().elapsed(start)));
+
+        if let Err(SnekcloudError::Timeout) = result {
+            // older nodes receive the request but have no handler that answers it
+            log::debug!(
+                "Node {} didn't answer the heartbeat, sending a one-way beat",
+                target
+            );
+            result = context
+                .emit(target, HEARTBEAT_LEGACY_BEAT.event(&payload))
+                .await
+                .wait_with_timeout(HEARTBEAT_TIMEOUT)
+                .await
+                .map(|_| None);
+        }
+
+        let info = match &result {
+            // the server is shutting down and the node didn't get a chance to answer
+            Err(SnekcloudError::Cancelled) => return result,
+            Ok(Some(latency)) => {
+                log::debug!("Latency to node {} is {} ms", target, latency.as_millis());
+                context.metrics().record_heartbeat_latency(target, *latency);
+                NodeInfo::alive(latency.as_millis() as u64)
             }
-            None => {
-                log::debug!("Node {} is not reachable: Timeout", target);
-                Self::insert_state(&mut *states.lock(), target.clone(), NodeInfo::dead());
+            Ok(None) => {
+                log::debug!("Node {} received a one-way heartbeat", target);
+                NodeInfo::reachable()
             }
-            _ => {}
+            Err(e) => {
+                log::debug!("Node {} is not reachable: {}", target, e);
+                NodeInfo::dead()
+            }
+        };
+        if let Some(change) = Self::record_state(&states, target, info, max_records) {
+            context.publish(&NODE_LIVENESS, change);
         }
+
+        result
     }
 }
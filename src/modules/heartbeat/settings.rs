@@ -4,29 +4,62 @@
  * See LICENSE for more information
  */
 
+use crate::utils::settings::{RestartSettings, SettingsIssue, ValidateSettings};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct HeartbeatSettings {
+    pub enabled: bool,
     pub output_file: Option<PathBuf>,
     pub interval_ms: u64,
     pub max_record_history: usize,
+    // restart needs to be last because it's a table
+    pub restart: RestartSettings,
 }
 
 impl Default for HeartbeatSettings {
     fn default() -> Self {
         Self {
+            enabled: true,
             output_file: None,
             interval_ms: 10000,
             max_record_history: 10,
+            restart: RestartSettings::default(),
         }
     }
 }
 
 impl HeartbeatSettings {
     pub fn interval(&self) -> Duration {
-        Duration::from_millis(self.interval_ms as u64)
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl ValidateSettings for HeartbeatSettings {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+
+        if self.interval_ms == 0 {
+            issues.push(SettingsIssue::new(
+                "interval_ms",
+                "Heartbeat interval must be greater than 0",
+            ));
+        }
+        if self.max_record_history == 0 {
+            issues.push(SettingsIssue::new(
+                "max_record_history",
+                "Record history size must be greater than 0",
+            ));
+        }
+        issues.extend(
+            self.restart
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("restart")),
+        );
+
+        issues
     }
 }
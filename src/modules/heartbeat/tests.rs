@@ -0,0 +1,322 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::modules::heartbeat::payloads::HeartbeatPayload;
+use crate::modules::heartbeat::{HeartbeatModule, HEARTBEAT_LEGACY_BEAT};
+use crate::modules::topics::NODE_LIVENESS;
+use crate::modules::Module;
+use crate::server::events::EventRegistry;
+use crate::server::tick_context::RunContext;
+use crate::testing::simulation::{LinkConditions, Simulation};
+use crate::testing::TestNetwork;
+use crate::utils::result::SnekcloudResult;
+use crate::utils::settings::Settings;
+use async_std::task;
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::net::TcpListener;
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(3600);
+/// The heartbeat interval of the simulated nodes
+const SIMULATED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Creates a network that only runs heartbeats
+fn heartbeat_network(count: usize) -> TestNetwork {
+    let mut network = TestNetwork::new(count);
+    for index in 0..count {
+        let settings = network.settings_mut(index);
+        settings.modules.heartbeat.interval_ms = 100;
+        settings.modules.nodes_refresh.enabled = false;
+    }
+
+    network
+}
+
+/// Returns the heartbeat states the node recorded for the other node
+fn recorded_states(network: &TestNetwork, index: usize, other: usize) -> Vec<Value> {
+    let params = json!({ "node": network.node_id(other) });
+    let history = task::block_on(
+        network
+            .context(index)
+            .call_control_method("heartbeat.history", params),
+    );
+
+    match history {
+        Ok(Value::Array(records)) => records.into_iter().map(|r| r["state"].clone()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[test]
+fn it_sees_all_nodes_alive() {
+    let mut network = heartbeat_network(3);
+    network.start();
+
+    assert!(network.wait_for(TIMEOUT, |network| network.all_alive()));
+    assert!(network.wait_for(TIMEOUT, |network| {
+        recorded_states(network, 0, 2).contains(&json!("Alive"))
+    }));
+}
+
+#[test]
+fn it_records_unreachable_nodes_as_dead() {
+    let mut network = heartbeat_network(3);
+    network.start_nodes(&[0, 1]);
+
+    assert!(network.wait_for(TIMEOUT, |network| {
+        recorded_states(network, 0, 2).contains(&json!("Dead"))
+    }));
+    assert!(network.wait_for(TIMEOUT, |network| network.sees_alive(0, 1)));
+    assert!(!network.sees_alive(0, 2));
+}
+
+#[test]
+fn it_cancels_stuck_heartbeats_on_shutdown() {
+    let mut network = heartbeat_network(2);
+    // the node accepts connections but never answers the key exchange
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    network.settings_mut(1).listen_addresses = vec![silent.local_addr().unwrap().to_string()];
+    network.settings_mut(0).shutdown_timeout_secs = 30;
+    network.start_nodes(&[0]);
+    task::block_on(task::sleep(Duration::from_millis(500)));
+
+    let start = Instant::now();
+    network.stop();
+    assert!(
+        start.elapsed() < TIMEOUT,
+        "Stopping took {:?}",
+        start.elapsed()
+    );
+}
+
+#[test]
+fn it_pings_nodes_on_request() {
+    let mut network = heartbeat_network(2);
+    network.start();
+    // the control methods are registered when the module runs
+    assert!(network.wait_for(TIMEOUT, |network| network.all_alive()));
+
+    let params = json!({ "node": network.node_id(1) });
+    let response = task::block_on(
+        network
+            .context(0)
+            .call_control_method("heartbeat.ping", params),
+    )
+    .unwrap();
+
+    assert_eq!(response["node"], json!(network.node_id(1)));
+    assert!(response["latency_ms"].is_u64());
+}
+
+/// Creates a simulation where every node sends heartbeats every second
+fn heartbeat_simulation(seed: u64, count: usize) -> Simulation {
+    let mut simulation = Simulation::new(seed, count);
+    let mut settings = Settings::default();
+    settings.modules.heartbeat.interval_ms = SIMULATED_INTERVAL.as_millis() as u64;
+    for index in 0..count {
+        let settings = settings.clone();
+        simulation.add_module(index, move || HeartbeatModule::new(&settings).boxed());
+    }
+
+    simulation
+}
+
+/// Returns the heartbeat records the simulated node has for the other node
+fn simulated_records(simulation: &mut Simulation, index: usize, other: usize) -> Vec<Value> {
+    let params = json!({ "node": simulation.node_id(other) });
+
+    match simulation.call(index, "heartbeat.history", params) {
+        Ok(Value::Array(records)) => records,
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the state of the last heartbeat the simulated node recorded for the other node
+fn last_state(simulation: &mut Simulation, index: usize, other: usize) -> Value {
+    simulated_records(simulation, index, other)
+        .last()
+        .map(|record| record["state"].clone())
+        .unwrap_or_default()
+}
+
+#[test]
+fn it_backs_off_from_crashed_nodes() {
+    let mut simulation = heartbeat_simulation(1, 2);
+    simulation.run_for(MINUTE);
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Alive"));
+
+    simulation.crash(1);
+    let sent_before = simulation.sent(0, 1);
+    simulation.run_for(HOUR);
+    let sent = simulation.sent(0, 1) - sent_before;
+
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Dead"));
+    // a dead node is only retried after the first 10 second wait that exceeds 100 intervals
+    let retry_secs = (SIMULATED_INTERVAL * 100).as_secs() + 10;
+    let expected = HOUR.as_secs().div_ceil(retry_secs);
+    assert_eq!(sent as u64, expected);
+
+    simulation.restart(1);
+    simulation.run_for(5 * MINUTE);
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Alive"));
+}
+
+#[test]
+fn it_stops_beating_removed_nodes() {
+    let mut simulation = heartbeat_simulation(5, 2);
+    simulation.run_for(MINUTE);
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Alive"));
+
+    simulation.remove_node(0, 1);
+    simulation.remove_node(1, 0);
+    simulation.run_for(SIMULATED_INTERVAL);
+    let sent_before = simulation.sent(0, 1);
+    simulation.run_for(10 * MINUTE);
+
+    assert_eq!(simulation.sent(0, 1), sent_before);
+}
+
+#[test]
+fn it_only_publishes_liveness_changes() {
+    let mut simulation = heartbeat_simulation(4, 2);
+    let liveness = simulation.context(0).subscribe(&NODE_LIVENESS);
+    simulation.run_for(MINUTE);
+    assert!(liveness.try_recv().is_err());
+
+    simulation.crash(1);
+    simulation.run_for(MINUTE);
+    let message = liveness
+        .try_recv()
+        .expect("No liveness change was published");
+    assert!(!message.alive);
+    assert_eq!(message.node, simulation.node_id(1));
+
+    simulation.restart(1);
+    simulation.run_for(5 * MINUTE);
+    assert!(
+        liveness
+            .try_recv()
+            .expect("No liveness change was published")
+            .alive
+    );
+    assert!(liveness.try_recv().is_err());
+}
+
+#[test]
+fn it_times_out_heartbeats_without_response() {
+    let mut simulation = heartbeat_simulation(2, 2);
+    simulation.set_conditions(
+        1,
+        0,
+        LinkConditions {
+            loss: 1.0,
+            ..LinkConditions::default()
+        },
+    );
+
+    simulation.run_for(Duration::from_secs(59));
+    assert!(simulated_records(&mut simulation, 0, 1).is_empty());
+    simulation.run_for(Duration::from_secs(2));
+    // the node still receives the one-way beat that is sent after the timeout
+    let records = simulated_records(&mut simulation, 0, 1);
+    assert_eq!(records.last().unwrap()["state"], json!("Alive"));
+    assert_eq!(records.last().unwrap()["ping"], Value::Null);
+}
+
+/// Sends one-way beats every second like older versions of the heartbeat module
+struct LegacyHeartbeatModule;
+
+#[async_trait]
+impl Module for LegacyHeartbeatModule {
+    fn name(&self) -> String {
+        "LegacyHeartbeatModule".to_string()
+    }
+
+    fn init(&mut self, _events: &mut EventRegistry) -> SnekcloudResult<()> {
+        Ok(())
+    }
+
+    fn boxed(self) -> Box<dyn Module + Send + Sync> {
+        Box::new(self)
+    }
+
+    async fn run(&mut self, mut context: RunContext) -> SnekcloudResult<()> {
+        loop {
+            for node in context.nodes() {
+                let payload = HeartbeatPayload::now(context.node_id().clone());
+                context
+                    .emit(node.id, HEARTBEAT_LEGACY_BEAT.event(&payload))
+                    .await;
+            }
+            context.clock().sleep(SIMULATED_INTERVAL).await;
+        }
+    }
+}
+
+#[test]
+fn it_keeps_older_nodes_alive() {
+    let mut simulation = Simulation::new(6, 2);
+    let settings = Settings::default();
+    simulation.add_module(0, move || HeartbeatModule::new(&settings).boxed());
+    simulation.add_module(1, || LegacyHeartbeatModule.boxed());
+    let liveness = simulation.context(0).subscribe(&NODE_LIVENESS);
+
+    simulation.run_for(10 * MINUTE);
+    let records = simulated_records(&mut simulation, 0, 1);
+    assert!(!records.is_empty());
+    assert!(records
+        .iter()
+        .all(|record| record["state"] == json!("Alive")));
+    assert!(records.iter().all(|record| record["ping"].is_null()));
+    assert!(liveness.try_recv().is_err());
+}
+
+#[test]
+fn it_recovers_when_a_partition_heals() {
+    let mut simulation = heartbeat_simulation(3, 3);
+    simulation.run_for(MINUTE);
+    simulation.partition(&[0], &[1, 2]);
+    simulation.run_for(10 * MINUTE);
+
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Dead"));
+    assert_eq!(last_state(&mut simulation, 0, 2), json!("Dead"));
+    assert_eq!(last_state(&mut simulation, 1, 2), json!("Alive"));
+
+    simulation.heal();
+    simulation.run_for(5 * MINUTE);
+    assert_eq!(last_state(&mut simulation, 0, 1), json!("Alive"));
+    assert_eq!(last_state(&mut simulation, 0, 2), json!("Alive"));
+}
+
+#[test]
+fn it_replays_a_simulation_with_the_same_seed() {
+    let run = |seed| {
+        let mut simulation = heartbeat_simulation(seed, 3);
+        simulation.set_default_conditions(LinkConditions {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(50),
+            loss: 0.2,
+        });
+        simulation.run_for(30 * MINUTE);
+
+        let mut records = Vec::new();
+        for index in 0..3 {
+            for other in (0..3).filter(|other| *other != index) {
+                records.extend(
+                    simulated_records(&mut simulation, index, other)
+                        .into_iter()
+                        .map(|record| (record["state"].clone(), record["ping"].clone())),
+                );
+            }
+        }
+        records
+    };
+
+    assert_eq!(run(7), run(7));
+}
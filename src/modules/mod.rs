@@ -4,18 +4,38 @@
  * See LICENSE for more information
  */
 
+use crate::server::events::EventRegistry;
 use crate::server::tick_context::RunContext;
 use crate::utils::result::SnekcloudResult;
+use crate::utils::settings::{RestartSettings, Settings};
 use async_trait::async_trait;
-use vented::server::VentedServer;
 
 pub mod heartbeat;
 pub mod nodes_refresh;
+pub mod registry;
+pub mod topics;
 
 #[async_trait]
 pub trait Module {
     fn name(&self) -> String;
-    fn init(&mut self, server: &mut VentedServer) -> SnekcloudResult<()>;
+    fn init(&mut self, events: &mut EventRegistry) -> SnekcloudResult<()>;
     fn boxed(self) -> Box<dyn Module + Send + Sync>;
     async fn run(&mut self, context: RunContext) -> SnekcloudResult<()>;
+
+    /// Returns how the module should be restarted when its run loop stops
+    fn restart_settings(&self) -> RestartSettings {
+        RestartSettings::default()
+    }
+
+    /// Called with the new settings when the configuration was reloaded.
+    /// The run loop of the module is stopped before and started again afterwards.
+    fn reconfigure(&mut self, _settings: &Settings) -> SnekcloudResult<()> {
+        Ok(())
+    }
+
+    /// Called when the server shuts down after the module stopped running
+    /// so that the module can flush its state
+    async fn shutdown(&mut self, _context: RunContext) -> SnekcloudResult<()> {
+        Ok(())
+    }
 }
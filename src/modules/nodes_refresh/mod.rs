@@ -4,31 +4,52 @@
  * See LICENSE for more information
  */
 
-use crate::data::node_data::NodeData;
+use crate::data::node_data::{node_file_path, NodeData};
+use crate::data::storage::ModuleStorage;
 use crate::modules::nodes_refresh::settings::NodesRefreshSettings;
+use crate::modules::topics::{NodeLiveness, NODE_LIVENESS};
 use crate::modules::Module;
+use crate::server::events::{EventRegistry, TypedEvent};
 use crate::server::tick_context::RunContext;
 use crate::utils::result::SnekcloudResult;
-use crate::utils::settings::get_settings;
-use async_std::task;
+use crate::utils::settings::{RestartSettings, Settings};
+use crate::utils::validate_node_id;
+use async_std::sync::{channel, Receiver, Sender};
 use async_trait::async_trait;
+use chrono::Utc;
+use futures::future::select;
+use futures::pin_mut;
 use parking_lot::Mutex;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use vented::event::Event;
+use std::time::Duration;
 use vented::server::data::Node;
-use vented::server::server_events::{NodeListPayload, NODE_LIST_REQUEST_EVENT};
-use vented::server::VentedServer;
+use vented::server::server_events::{NodeListPayload, NODE_LIST_EVENT, NODE_LIST_REQUEST_EVENT};
 use vented::stream::PublicKey;
 
 pub mod settings;
+#[cfg(test)]
+mod tests;
+
+const NODES_REFRESH_METHOD: &str = "nodes.refresh";
+const NODE_LIST_REQUEST: TypedEvent<()> = TypedEvent::new(NODE_LIST_REQUEST_EVENT);
+const NODE_LIST: TypedEvent<NodeListPayload> = TypedEvent::new(NODE_LIST_EVENT);
+const NODE_LIST_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const NODES_REFRESH_STORAGE_NAMESPACE: &str = "nodesrefresh";
+/// The unix timestamp of the last time the node lists were requested
+const LAST_REFRESH_KEY: &str = "last_refresh";
 
 pub struct NodesRefreshModule {
     nodes: Arc<Mutex<HashMap<String, Node>>>,
-    update_required: Arc<AtomicBool>,
+    unsaved_nodes: Arc<Mutex<HashSet<String>>>,
     settings: NodesRefreshSettings,
+    node_data_dir: PathBuf,
+    max_nodes_per_list: Arc<AtomicUsize>,
+    refresh_sender: Sender<()>,
+    refresh_receiver: Receiver<()>,
 }
 
 #[async_trait]
@@ -37,44 +58,56 @@ impl Module for NodesRefreshModule {
         "node_list_refresh".to_string()
     }
 
-    fn init(&mut self, server: &mut VentedServer) -> SnekcloudResult<()> {
-        {
-            let mut node_list = self.nodes.lock();
-            for node in server.nodes() {
-                node_list.insert(node.id.clone(), node);
-            }
-        }
-        server.on("conn:node_list", {
+    fn init(&mut self, events: &mut EventRegistry) -> SnekcloudResult<()> {
+        events.on_typed(&NODE_LIST, {
             let nodes = Arc::clone(&self.nodes);
-            let update_required = Arc::clone(&self.update_required);
-
-            move |event| {
-                let nodes = Arc::clone(&nodes);
-                let update_required = Arc::clone(&update_required);
-                Box::pin(async move {
-                    let mut nodes = nodes.lock();
-                    let mut new_nodes = false;
-
-                    for node in event.get_payload::<NodeListPayload>().ok()?.nodes {
-                        if !nodes.contains_key(&node.id) {
-                            nodes.insert(
-                                node.id.clone(),
-                                Node {
-                                    id: node.id,
-                                    trusted: false,
-                                    public_key: PublicKey::from(node.public_key),
-                                    addresses: node.addresses,
-                                },
-                            );
-                            new_nodes = true;
-                        }
-                    }
+            let unsaved_nodes = Arc::clone(&self.unsaved_nodes);
+            let max_nodes_per_list = Arc::clone(&self.max_nodes_per_list);
+            let node_data_dir = self.node_data_dir.clone();
+            let metrics = events.metrics();
 
-                    if new_nodes {
-                        update_required.store(true, Ordering::Relaxed)
+            move |origin, mut payload: NodeListPayload| {
+                let origin = origin.unwrap_or_else(|| "unknown".to_string());
+                let max_nodes_per_list = max_nodes_per_list.load(Ordering::Relaxed);
+                if payload.nodes.len() > max_nodes_per_list {
+                    log::warn!(
+                        "Node {} sent a list of {} nodes. Only the first {} are used",
+                        origin,
+                        payload.nodes.len(),
+                        max_nodes_per_list
+                    );
+                    metrics.record_limited(&origin, "node_list_size");
+                    payload.nodes.truncate(max_nodes_per_list);
+                }
+                let mut nodes = nodes.lock();
+
+                for node in payload.nodes {
+                    if nodes.contains_key(&node.id) {
+                        continue;
+                    }
+                    if !validate_node_id(&node.id) {
+                        log::warn!(
+                            "Node {} sent a node with the invalid id {:?}",
+                            origin,
+                            node.id
+                        );
+                        continue;
                     }
-                    None
-                })
+                    let node = Node {
+                        id: node.id,
+                        trusted: false,
+                        public_key: PublicKey::from(node.public_key),
+                        addresses: node.addresses,
+                    };
+                    // the node watcher adds the written node to the server and announces it
+                    if let Err(e) = Self::save_node(&node_data_dir, &node) {
+                        log::error!("Failed to write the node data of {}: {}", node.id, e);
+                        unsaved_nodes.lock().insert(node.id.clone());
+                    }
+                    nodes.insert(node.id.clone(), node);
+                }
+
+                async { None }
             }
         });
 
@@ -85,45 +118,147 @@ impl Module for NodesRefreshModule {
         Box::new(self)
     }
 
-    async fn run(&mut self, mut context: RunContext) -> SnekcloudResult<()> {
-        loop {
-            for node in context.living_nodes().iter().filter(|node| node.trusted) {
-                context
-                    .emit(node.id.clone(), Event::new(NODE_LIST_REQUEST_EVENT))
-                    .await;
+    fn restart_settings(&self) -> RestartSettings {
+        self.settings.restart.clone()
+    }
+
+    fn reconfigure(&mut self, settings: &Settings) -> SnekcloudResult<()> {
+        self.settings = settings.modules.nodes_refresh.clone();
+        self.max_nodes_per_list
+            .store(self.settings.max_nodes_per_list, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    async fn run(&mut self, context: RunContext) -> SnekcloudResult<()> {
+        {
+            let mut node_list = self.nodes.lock();
+            for node in context.nodes() {
+                node_list.entry(node.id.clone()).or_insert(node);
+            }
+        }
+        context.register_control_method(NODES_REFRESH_METHOD, {
+            let sender = self.refresh_sender.clone();
+            move |_| {
+                // a full channel means that a refresh is already pending
+                let _ = sender.try_send(());
+                async move { Ok(json!({ "triggered": true })) }
             }
-            if self.update_required.load(Ordering::Relaxed) {
-                self.write_node_data();
+        });
+        let liveness = context.subscribe(&NODE_LIVENESS);
+        let storage = context.storage(NODES_REFRESH_STORAGE_NAMESPACE)?;
+
+        // the nodes of a refresh shortly before a restart are already in the node data directory
+        let remaining = Self::remaining_interval(&storage, self.settings.update_interval());
+        if remaining > Duration::from_secs(0) {
+            log::debug!("Refreshing node lists in {} s", remaining.as_secs());
+            self.wait_for_refresh(&context, &liveness, remaining).await;
+        }
+        loop {
+            // nodes that revived until now are covered by this refresh
+            while liveness.try_recv().is_ok() {}
+            context
+                .multicast(
+                    |node| node.trusted,
+                    NODE_LIST_REQUEST.event(&()),
+                    NODE_LIST_REQUEST_TIMEOUT,
+                )
+                .await
+                .log(NODE_LIST_REQUEST.name());
+            self.write_node_data();
+            if let Err(e) = storage.set(LAST_REFRESH_KEY, &Utc::now().timestamp()) {
+                log::warn!("Failed to store the time of the refresh: {}", e);
             }
 
-            task::sleep(self.settings.update_interval()).await
+            self.wait_for_refresh(&context, &liveness, self.settings.update_interval())
+                .await;
         }
     }
+
+    async fn shutdown(&mut self, _context: RunContext) -> SnekcloudResult<()> {
+        self.write_node_data();
+
+        Ok(())
+    }
 }
 
 impl NodesRefreshModule {
-    pub fn new() -> Self {
+    /// Waits for the duration or until a refresh is requested or a node comes back alive
+    async fn wait_for_refresh(
+        &self,
+        context: &RunContext,
+        liveness: &Receiver<NodeLiveness>,
+        duration: Duration,
+    ) {
+        let node_revived = async {
+            while let Ok(liveness) = liveness.recv().await {
+                if liveness.alive {
+                    log::debug!("Node {} is alive. Refreshing node lists", liveness.node);
+                    break;
+                }
+            }
+        };
+        let refresh_requested = self.refresh_receiver.recv();
+        pin_mut!(node_revived);
+        pin_mut!(refresh_requested);
+        let _ = context
+            .clock()
+            .timeout(duration, select(refresh_requested, node_revived))
+            .await;
+    }
+
+    /// Returns how much of the update interval is left since the last stored refresh
+    fn remaining_interval(storage: &ModuleStorage, interval: Duration) -> Duration {
+        let last_refresh = match storage.get::<i64>(LAST_REFRESH_KEY) {
+            Ok(last_refresh) => last_refresh,
+            Err(e) => {
+                log::warn!("Failed to read the time of the last refresh: {}", e);
+                None
+            }
+        };
+
+        last_refresh
+            .map(|timestamp| {
+                let elapsed = (Utc::now().timestamp() - timestamp).max(0) as u64;
+                interval.saturating_sub(Duration::from_secs(elapsed))
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn new(settings: &Settings) -> Self {
+        let (refresh_sender, refresh_receiver) = channel(1);
+        let node_data_dir = settings.node_data_dir.clone();
+        let settings = settings.modules.nodes_refresh.clone();
         Self {
+            refresh_sender,
+            refresh_receiver,
             nodes: Arc::new(Mutex::new(HashMap::new())),
-            settings: get_settings().modules.nodes_refresh,
-            update_required: Arc::new(AtomicBool::new(false)),
+            max_nodes_per_list: Arc::new(AtomicUsize::new(settings.max_nodes_per_list)),
+            settings,
+            node_data_dir,
+            unsaved_nodes: Arc::new(Mutex::new(HashSet::new())),
         }
     }
 
+    /// Writes the discovered nodes that couldn't be written when they were received again.
+    /// Files of nodes that were known before aren't touched.
     fn write_node_data(&self) {
-        let nodes_folder = get_settings().node_data_dir;
-        self.nodes
-            .lock()
-            .values()
-            .cloned()
-            .map(|node| NodeData::with_addresses(node.id, node.addresses, node.public_key))
-            .for_each(|data| {
-                let mut path = nodes_folder.clone();
-                path.push(PathBuf::from(format!("{}.toml", data.id)));
-
-                if let Err(e) = data.write_to_file(path) {
-                    log::error!("Failed to write updated node data: {}", e);
-                }
-            });
+        let unsaved_nodes: Vec<String> = self.unsaved_nodes.lock().drain().collect();
+        let nodes = self.nodes.lock();
+
+        for node in unsaved_nodes.iter().filter_map(|id| nodes.get(id)) {
+            if let Err(e) = Self::save_node(&self.node_data_dir, node) {
+                log::error!("Failed to write the node data of {}: {}", node.id, e);
+                self.unsaved_nodes.lock().insert(node.id.clone());
+            }
+        }
+    }
+
+    /// Writes the node to its file in the node data directory
+    fn save_node(node_data_dir: &Path, node: &Node) -> SnekcloudResult<()> {
+        let data =
+            NodeData::with_addresses(node.id.clone(), node.addresses.clone(), node.public_key);
+
+        data.write_to_file(node_file_path(node_data_dir, &node.id))
     }
 }
@@ -4,18 +4,27 @@
  * See LICENSE for more information
  */
 
+use crate::utils::settings::{RestartSettings, SettingsIssue, ValidateSettings};
 use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct NodesRefreshSettings {
+    pub enabled: bool,
     pub update_interval_ms: u64,
+    /// The maximum number of nodes accepted from a single node list
+    pub max_nodes_per_list: usize,
+    // restart needs to be last because it's a table
+    pub restart: RestartSettings,
 }
 
 impl Default for NodesRefreshSettings {
     fn default() -> Self {
         Self {
+            enabled: true,
             update_interval_ms: 3600000,
+            max_nodes_per_list: 1000,
+            restart: RestartSettings::default(),
         }
     }
 }
@@ -25,3 +34,30 @@ impl NodesRefreshSettings {
         Duration::from_millis(self.update_interval_ms)
     }
 }
+
+impl ValidateSettings for NodesRefreshSettings {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+
+        if self.update_interval_ms == 0 {
+            issues.push(SettingsIssue::new(
+                "update_interval_ms",
+                "Update interval must be greater than 0",
+            ));
+        }
+        if self.max_nodes_per_list == 0 {
+            issues.push(SettingsIssue::new(
+                "max_nodes_per_list",
+                "Max nodes per list must be greater than 0",
+            ));
+        }
+        issues.extend(
+            self.restart
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("restart")),
+        );
+
+        issues
+    }
+}
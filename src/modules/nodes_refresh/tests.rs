@@ -0,0 +1,59 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::data::node_data::{node_file_path, NodeData};
+use crate::modules::topics::NODE_DISCOVERED;
+use crate::testing::TestNetwork;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Creates a network of three nodes where the first and the last node
+/// only know each other through the node in the middle
+fn chained_network() -> TestNetwork {
+    let mut network = TestNetwork::new(3);
+    network.forget(0, 2);
+    network.forget(2, 0);
+    for index in 0..3 {
+        network.settings_mut(index).modules.heartbeat.interval_ms = 100;
+    }
+
+    network
+}
+
+#[test]
+fn it_learns_nodes_from_trusted_nodes() {
+    let mut network = chained_network();
+    network.trust(0, 1);
+    network.start();
+
+    let discovered = network.context(0).subscribe(&NODE_DISCOVERED);
+    let node_file = node_file_path(&network.settings(0).node_data_dir, &network.node_id(2));
+
+    assert!(network.wait_for(TIMEOUT, |network| {
+        network.context(0).has_node(&network.node_id(2)) && node_file.exists()
+    }));
+    let data = NodeData::from_file(node_file).expect("Failed to read the discovered node");
+    assert_eq!(data.addresses, network.settings(2).listen_addresses);
+    // the node is announced once the node watcher picked up its file
+    assert!(network.wait_for(TIMEOUT, |network| {
+        discovered
+            .try_recv()
+            .map(|message| message.node == network.node_id(2))
+            .unwrap_or(false)
+    }));
+}
+
+#[test]
+fn it_ignores_untrusted_nodes() {
+    let mut network = chained_network();
+    network.start();
+
+    assert!(network.wait_for(TIMEOUT, |network| network.all_alive()));
+    assert!(!network.wait_for(Duration::from_secs(1), |network| {
+        network.context(0).has_node(&network.node_id(2))
+    }));
+}
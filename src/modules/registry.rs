@@ -0,0 +1,82 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::modules::heartbeat::HeartbeatModule;
+use crate::modules::nodes_refresh::NodesRefreshModule;
+use crate::modules::Module;
+use crate::utils::settings::{ModuleSettings, Settings};
+use std::collections::BTreeMap;
+
+pub type ModuleConstructor = fn(&Settings) -> Box<dyn Module + Send + Sync>;
+
+struct ModuleEntry {
+    enabled: fn(&ModuleSettings) -> bool,
+    constructor: ModuleConstructor,
+}
+
+/// Maps the names of the modules in the configuration to their constructors
+pub struct ModuleRegistry {
+    modules: BTreeMap<&'static str, ModuleEntry>,
+}
+
+impl Default for ModuleRegistry {
+    /// Creates a registry containing all builtin modules
+    fn default() -> Self {
+        let mut registry = Self {
+            modules: BTreeMap::new(),
+        };
+        registry.register(
+            "heartbeat",
+            |settings| settings.heartbeat.enabled,
+            |settings| HeartbeatModule::new(settings).boxed(),
+        );
+        registry.register(
+            "nodes_refresh",
+            |settings| settings.nodes_refresh.enabled,
+            |settings| NodesRefreshModule::new(settings).boxed(),
+        );
+
+        registry
+    }
+}
+
+impl ModuleRegistry {
+    /// Registers a module with the name of its settings table
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        enabled: fn(&ModuleSettings) -> bool,
+        constructor: ModuleConstructor,
+    ) {
+        self.modules.insert(
+            name,
+            ModuleEntry {
+                enabled,
+                constructor,
+            },
+        );
+    }
+
+    /// Returns the names of all registered modules
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.modules.keys().copied()
+    }
+
+    /// Creates all modules that are enabled in the given settings
+    pub fn build(&self, settings: &Settings) -> Vec<Box<dyn Module + Send + Sync>> {
+        self.modules
+            .iter()
+            .filter_map(|(name, entry)| {
+                if (entry.enabled)(&settings.modules) {
+                    Some((entry.constructor)(settings))
+                } else {
+                    log::info!("Module {} is disabled", name);
+                    None
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::bus::Topic;
+
+/// Published by the heartbeat module when a node becomes reachable or unreachable
+pub const NODE_LIVENESS: Topic<NodeLiveness> = Topic::new("node:liveness");
+
+/// Published by the nodes refresh module when a new node was discovered
+pub const NODE_DISCOVERED: Topic<NodeDiscovered> = Topic::new("node:discovered");
+
+/// Published by the server when the file of a node was removed or the node is no longer trusted
+pub const NODE_REMOVED: Topic<NodeRemoved> = Topic::new("node:removed");
+
+#[derive(Clone, Debug)]
+pub struct NodeLiveness {
+    pub node: String,
+    pub alive: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeDiscovered {
+    pub node: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct NodeRemoved {
+    pub node: String,
+    pub reason: RemovalReason,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RemovalReason {
+    /// The node file was deleted and the node is no longer known
+    Deleted,
+    /// The node is still known but was removed from the trusted nodes
+    Untrusted,
+}
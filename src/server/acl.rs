@@ -0,0 +1,151 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::rpc::RPC_RESPONSE_EVENT;
+use crate::utils::settings::{AclPolicy, AclSettings};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use vented::event::Event;
+use vented::server::data::NodeData;
+
+/// Decides which nodes may send an event to the handlers of the modules
+#[derive(Clone)]
+pub struct Acl {
+    settings: Arc<RwLock<AclSettings>>,
+    nodes: Arc<Mutex<HashMap<String, NodeData>>>,
+    /// The nodes and events whose rejection has already been logged
+    rejected: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl Acl {
+    pub fn new(settings: AclSettings, nodes: Arc<Mutex<HashMap<String, NodeData>>>) -> Self {
+        Self {
+            settings: Arc::new(RwLock::new(settings)),
+            nodes,
+            rejected: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Replaces the rules
+    pub fn update(&self, settings: AclSettings) {
+        *self.settings.write() = settings;
+    }
+
+    /// Returns if the origin of the event may send it.
+    /// Only the first rejected event of a node is logged until the node
+    /// is allowed to send the event again so that a flood doesn't flood the log as well.
+    pub fn check(&self, event: &Event) -> bool {
+        let allowed = self.allows(&event.name, event.origin.as_ref());
+        let origin = event.origin.as_deref().unwrap_or("unknown");
+        let key = (origin.to_string(), event.name.clone());
+        let mut rejected = self.rejected.lock();
+
+        if allowed {
+            rejected.remove(&key);
+        } else if rejected.insert(key) {
+            log::warn!(
+                "Rejected {} event from untrusted node {}. Dropping its events until it is trusted",
+                event.name,
+                origin
+            );
+        }
+
+        allowed
+    }
+
+    /// Returns if the node may send the event.
+    /// Events without an origin are treated as sent by an untrusted node.
+    /// Responses are always allowed as they are only accepted for requests sent by this node.
+    pub fn allows(&self, event_name: &str, origin: Option<&String>) -> bool {
+        if event_name == RPC_RESPONSE_EVENT {
+            return true;
+        }
+        match self.settings.read().policy(event_name) {
+            AclPolicy::Open => true,
+            AclPolicy::Trusted => origin
+                .and_then(|origin| {
+                    self.nodes
+                        .lock()
+                        .get(origin)
+                        .map(|node| node.node().trusted)
+                })
+                .unwrap_or(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vented::server::data::Node;
+    use vented::stream::PublicKey;
+
+    fn acl(default: AclPolicy) -> Acl {
+        let mut nodes = HashMap::new();
+        for (id, trusted) in &[("trusted", true), ("untrusted", false)] {
+            let node = Node {
+                id: id.to_string(),
+                public_key: PublicKey::from([0; 32]),
+                addresses: Vec::new(),
+                trusted: *trusted,
+            };
+            nodes.insert(id.to_string(), NodeData::from(node));
+        }
+        let settings = AclSettings {
+            default,
+            ..AclSettings::default()
+        };
+
+        Acl::new(settings, Arc::new(Mutex::new(nodes)))
+    }
+
+    #[test]
+    fn it_allows_only_known_trusted_nodes() {
+        let acl = acl(AclPolicy::Trusted);
+
+        assert!(acl.allows("event", Some(&"trusted".to_string())));
+        assert!(!acl.allows("event", Some(&"untrusted".to_string())));
+        assert!(!acl.allows("event", Some(&"unknown".to_string())));
+        assert!(!acl.allows("event", None));
+    }
+
+    #[test]
+    fn it_allows_every_origin_for_open_events() {
+        let acl = acl(AclPolicy::Open);
+
+        assert!(acl.allows("event", Some(&"unknown".to_string())));
+        assert!(acl.allows("event", None));
+    }
+
+    #[test]
+    fn it_always_allows_responses() {
+        let acl = acl(AclPolicy::Trusted);
+
+        assert!(acl.allows(RPC_RESPONSE_EVENT, Some(&"untrusted".to_string())));
+    }
+
+    #[test]
+    fn it_remembers_rejected_nodes_until_they_are_allowed() {
+        let acl = acl(AclPolicy::Trusted);
+        let mut event = Event::new("event");
+        event.origin = Some("untrusted".to_string());
+        let key = ("untrusted".to_string(), "event".to_string());
+
+        assert!(!acl.check(&event));
+        assert!(!acl.check(&event));
+        assert_eq!(acl.rejected.lock().len(), 1);
+
+        acl.nodes
+            .lock()
+            .get_mut("untrusted")
+            .unwrap()
+            .node_mut()
+            .trusted = true;
+        assert!(acl.check(&event));
+        assert!(!acl.rejected.lock().contains(&key));
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::control::ControlMethods;
+use crate::server::http::{HttpRequest, HttpResponse};
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use serde_json::{json, Value};
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Answers a request to the read-only json api.
+/// The data is provided by the control methods of the server and its modules.
+pub async fn handle_request(methods: &ControlMethods, request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse::new(405, "text/plain", "Method Not Allowed");
+    }
+    let path = request.path.split('?').next().unwrap_or_default();
+    let segments: Option<Vec<String>> = path
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(percent_decode)
+        .collect();
+    let segments = match segments {
+        Some(segments) => segments,
+        None => return HttpResponse::bad_request(),
+    };
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+    let result = match segments.as_slice() {
+        ["health"] => return health(methods).await,
+        ["nodes"] => methods.call("nodes.list", json!({})).await,
+        ["nodes", node, "heartbeats"] => {
+            methods
+                .call("heartbeat.history", json!({ "node": node }))
+                .await
+        }
+        ["modules"] => methods.call("modules.list", json!({})).await,
+        _ => return HttpResponse::not_found(),
+    };
+
+    json_response(result)
+}
+
+/// Returns an error status if any module failed
+async fn health(methods: &ControlMethods) -> HttpResponse {
+    let modules = match methods.call("modules.list", json!({})).await {
+        Ok(modules) => modules,
+        Err(e) => return json_response(Err(e)),
+    };
+    let failed: Vec<&Value> = modules
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|module| module["state"] == "failed")
+        .map(|module| &module["name"])
+        .collect();
+
+    if failed.is_empty() {
+        HttpResponse::new(200, JSON_CONTENT_TYPE, json!({ "status": "ok" }))
+    } else {
+        HttpResponse::new(
+            503,
+            JSON_CONTENT_TYPE,
+            json!({ "status": "failed", "failed_modules": failed }),
+        )
+    }
+}
+
+/// Decodes the percent encoded characters of a path segment.
+/// Returns None if an escape is invalid or the result isn't valid utf-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = segment.bytes();
+    let mut decoded = Vec::with_capacity(segment.len());
+
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = (bytes.next()? as char).to_digit(16)?;
+            let low = (bytes.next()? as char).to_digit(16)?;
+            decoded.push((high * 16 + low) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn json_response(result: SnekcloudResult<Value>) -> HttpResponse {
+    match result {
+        Ok(value) => HttpResponse::new(200, JSON_CONTENT_TYPE, value),
+        Err(e) => {
+            // unknown methods belong to modules that are disabled
+            let status = match e {
+                SnekcloudError::UnknownNode(_) | SnekcloudError::UnknownMethod(_) => 404,
+                _ => 500,
+            };
+            HttpResponse::new(status, JSON_CONTENT_TYPE, json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    fn get(methods: &ControlMethods, path: &str) -> HttpResponse {
+        let request = HttpRequest {
+            method: "GET".to_string(),
+            path: path.to_string(),
+        };
+
+        task::block_on(handle_request(methods, request))
+    }
+
+    #[test]
+    fn it_decodes_node_ids_in_paths() {
+        let methods = ControlMethods::default();
+        methods.register("heartbeat.history", |params| async move { Ok(params) });
+
+        let response = get(&methods, "/nodes/ab%2Bc%2Fd%3D%3D/heartbeats?limit=1");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, json!({ "node": "ab+c/d==" }).to_string());
+
+        let response = get(&methods, "/nodes/node-1/heartbeats");
+        assert_eq!(response.body, json!({ "node": "node-1" }).to_string());
+    }
+
+    #[test]
+    fn it_rejects_invalid_escapes() {
+        let methods = ControlMethods::default();
+
+        assert_eq!(get(&methods, "/nodes/a%2/heartbeats").status, 400);
+        assert_eq!(get(&methods, "/nodes/a%zz/heartbeats").status, 400);
+        assert_eq!(get(&methods, "/nodes/a%+1/heartbeats").status, 400);
+        assert_eq!(get(&methods, "/nodes/%FF/heartbeats").status, 400);
+    }
+
+    #[test]
+    fn it_returns_not_found_for_unknown_paths() {
+        let methods = ControlMethods::default();
+        methods.register("nodes.list", |_| async { Ok(json!([])) });
+
+        assert_eq!(get(&methods, "/nodes").status, 200);
+        assert_eq!(get(&methods, "/nodes/a/b").status, 404);
+        assert_eq!(get(&methods, "/nope").status, 404);
+    }
+}
@@ -0,0 +1,84 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use async_std::sync::{channel, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use std::any::Any;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// The number of messages a subscriber can fall behind before messages are dropped
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+type Subscriber = Box<dyn Any + Send + Sync>;
+
+/// A topic name bound to the type of its messages
+pub struct Topic<T> {
+    name: &'static str,
+    message: PhantomData<fn() -> T>,
+}
+
+impl<T> Topic<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            message: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// A local publish/subscribe bus that lets modules react to each other
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<HashMap<&'static str, Vec<Subscriber>>>>,
+}
+
+impl EventBus {
+    /// Returns a receiver for all messages published on the topic after subscribing
+    pub fn subscribe<T: Send + 'static>(&self, topic: &Topic<T>) -> Receiver<T> {
+        let (sender, receiver) = channel(SUBSCRIBER_CAPACITY);
+        self.subscribers
+            .lock()
+            .entry(topic.name())
+            .or_default()
+            .push(Box::new(sender));
+
+        receiver
+    }
+
+    /// Sends the message to every subscriber of the topic.
+    /// Subscribers that can't keep up miss the message.
+    pub fn publish<T: Clone + Send + 'static>(&self, topic: &Topic<T>, message: T) {
+        let mut subscribers = self.subscribers.lock();
+        let subscribers = match subscribers.get_mut(topic.name()) {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+
+        subscribers.retain(|subscriber| {
+            let sender = match subscriber.downcast_ref::<Sender<T>>() {
+                Some(sender) => sender,
+                None => {
+                    log::error!("Topic {} is used with different types", topic.name());
+                    return true;
+                }
+            };
+            match sender.try_send(message.clone()) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Dropping message on {} for a slow subscriber", topic.name());
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
@@ -0,0 +1,82 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use async_std::task;
+use futures::future::{select, BoxFuture, Either};
+use futures::{pin_mut, Future};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Provides the current time and timers
+pub trait TimeSource: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The time source of the operating system
+struct SystemTime;
+
+impl TimeSource for SystemTime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(task::sleep(duration))
+    }
+}
+
+/// The clock modules measure time and wait with.
+/// It can be replaced with a virtual clock to run modules in simulated time.
+#[derive(Clone)]
+pub struct Clock {
+    source: Arc<dyn TimeSource>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(SystemTime)
+    }
+}
+
+impl Clock {
+    pub fn new<T: TimeSource + 'static>(source: T) -> Self {
+        Self {
+            source: Arc::new(source),
+        }
+    }
+
+    /// Returns the current time of the clock
+    pub fn now(&self) -> Instant {
+        self.source.now()
+    }
+
+    /// Returns the time passed on this clock since the given instant
+    pub fn elapsed(&self, since: Instant) -> Duration {
+        self.now().saturating_duration_since(since)
+    }
+
+    /// Waits for the given duration
+    pub fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.source.sleep(duration)
+    }
+
+    /// Waits for the future for at most the given duration
+    pub async fn timeout<F: Future>(
+        &self,
+        duration: Duration,
+        future: F,
+    ) -> SnekcloudResult<F::Output> {
+        let sleep = self.sleep(duration);
+        pin_mut!(future);
+
+        match select(future, sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right(_) => Err(SnekcloudError::Timeout),
+        }
+    }
+}
@@ -0,0 +1,303 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use futures::future::BoxFuture;
+use futures::Future;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+const JSONRPC_VERSION: &str = "2.0";
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+pub type ControlMethod =
+    Arc<dyn Fn(Value) -> BoxFuture<'static, SnekcloudResult<Value>> + Send + Sync>;
+
+/// The methods that can be called via the control socket
+#[derive(Clone, Default)]
+pub struct ControlMethods {
+    methods: Arc<Mutex<HashMap<String, ControlMethod>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcRequest {
+    pub fn new<S: ToString>(id: u64, method: S, params: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id: Value::from(id),
+            method: method.to_string(),
+            params,
+        }
+    }
+}
+
+impl RpcResponse {
+    fn success(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error<S: ToString>(id: Value, code: i64, message: S) -> Self {
+        Self {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            id,
+            result: None,
+            error: Some(RpcError {
+                code,
+                message: message.to_string(),
+            }),
+        }
+    }
+
+    /// Converts the response into the result of the call
+    pub fn into_result(self) -> SnekcloudResult<Value> {
+        match self.error {
+            Some(error) if error.code == METHOD_NOT_FOUND => {
+                Err(SnekcloudError::UnknownMethod(error.message))
+            }
+            Some(error) => Err(SnekcloudError::Rpc(error.code, error.message)),
+            None => Ok(self.result.unwrap_or(Value::Null)),
+        }
+    }
+}
+
+impl ControlMethods {
+    /// Registers a method with the given name.
+    /// An existing method with the same name gets replaced.
+    pub fn register<F, Fut>(&self, name: &str, method: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = SnekcloudResult<Value>> + Send + 'static,
+    {
+        self.methods.lock().insert(
+            name.to_string(),
+            Arc::new(move |params| Box::pin(method(params))),
+        );
+    }
+
+    /// Calls the method with the given name
+    pub async fn call(&self, name: &str, params: Value) -> SnekcloudResult<Value> {
+        let method = self.methods.lock().get(name).cloned();
+
+        match method {
+            Some(method) => method(params).await,
+            None => Err(SnekcloudError::UnknownMethod(format!(
+                "Unknown method {}",
+                name
+            ))),
+        }
+    }
+
+    /// Handles a single request line and returns the serialized response
+    pub async fn handle_line(&self, line: &str) -> String {
+        let response = match serde_json::from_str::<RpcRequest>(line) {
+            Ok(request) => self.handle_request(request).await,
+            Err(e) => RpcResponse::error(Value::Null, PARSE_ERROR, e),
+        };
+
+        serde_json::to_string(&response).unwrap_or_default()
+    }
+
+    /// Calls the method of the request
+    async fn handle_request(&self, request: RpcRequest) -> RpcResponse {
+        let method = self.methods.lock().get(&request.method).cloned();
+        let method = match method {
+            Some(method) => method,
+            None => {
+                return RpcResponse::error(
+                    request.id,
+                    METHOD_NOT_FOUND,
+                    format!("Unknown method {}", request.method),
+                )
+            }
+        };
+        let params = if request.params.is_null() {
+            Value::Object(Default::default())
+        } else {
+            request.params
+        };
+        log::debug!("Handling control request {}", request.method);
+
+        match method(params).await {
+            Ok(result) => RpcResponse::success(request.id, result),
+            Err(SnekcloudError::JsonError(e)) => RpcResponse::error(request.id, INVALID_PARAMS, e),
+            Err(e) => RpcResponse::error(request.id, SERVER_ERROR, e),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::{call, listen};
+
+/// Calls a method on the control socket of a running server
+#[cfg(not(unix))]
+pub fn call(_path: &std::path::Path, _method: &str, _params: Value) -> SnekcloudResult<Value> {
+    Err(SnekcloudError::IoError(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "The control socket is only supported on unix systems",
+    )))
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::{ControlMethods, RpcRequest, RpcResponse};
+    use crate::utils::result::{SnekcloudError, SnekcloudResult};
+    use async_std::io::BufReader;
+    use async_std::os::unix::net::{UnixListener, UnixStream};
+    use async_std::prelude::*;
+    use async_std::task;
+    use serde_json::Value;
+    use std::fs::{self, DirBuilder};
+    use std::io::{self, BufRead, Write};
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+
+    /// Starts listening for control requests on the unix socket with the given path.
+    /// The socket is only accessible by the owning user.
+    pub async fn listen(path: PathBuf, methods: ControlMethods) -> SnekcloudResult<()> {
+        remove_stale_socket(&path)?;
+        let listener = bind_private(&path).await?;
+        log::info!("Control socket listening on {:?}", path);
+
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+
+            while let Some(stream) = incoming.next().await {
+                match stream {
+                    Ok(stream) => {
+                        let methods = methods.clone();
+                        task::spawn(async move {
+                            if let Err(e) = handle_connection(stream, methods).await {
+                                log::debug!("Control connection closed: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("Failed to accept control connection: {}", e),
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Removes a socket left behind by an instance that didn't shut down cleanly.
+    /// Fails if the path isn't a socket or another instance still accepts connections on it.
+    fn remove_stale_socket(path: &Path) -> SnekcloudResult<()> {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if !metadata.file_type().is_socket() {
+            return Err(SnekcloudError::IoError(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{:?} exists and is not a socket", path),
+            )));
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(SnekcloudError::ControlSocketInUse(path.to_path_buf()));
+        }
+        log::debug!("Removing stale control socket {:?}", path);
+        fs::remove_file(path)?;
+
+        Ok(())
+    }
+
+    /// Binds the socket in a directory that only the owning user can access
+    /// and moves it to the path once its permissions are restricted.
+    /// Other users can't connect in between because they can't reach the directory.
+    async fn bind_private(path: &Path) -> SnekcloudResult<UnixListener> {
+        let mut dir_name = path.file_name().unwrap_or_default().to_os_string();
+        dir_name.push(".tmp");
+        let dir = path.with_file_name(dir_name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let tmp_path = dir.join("socket");
+
+        let result = async {
+            let listener = UnixListener::bind(&tmp_path).await?;
+            fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&tmp_path, path)?;
+
+            Ok(listener)
+        }
+        .await;
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            log::warn!("Failed to remove {:?}: {}", dir, e);
+        }
+
+        result
+    }
+
+    /// Handles newline delimited requests on the stream
+    async fn handle_connection(stream: UnixStream, methods: ControlMethods) -> SnekcloudResult<()> {
+        let mut writer = stream.clone();
+        let mut lines = BufReader::new(stream).lines();
+
+        while let Some(line) = lines.next().await {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut response = methods.handle_line(&line).await;
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls a method on the control socket of a running server
+    pub fn call(path: &Path, method: &str, params: Value) -> SnekcloudResult<Value> {
+        let mut stream = std::os::unix::net::UnixStream::connect(path)?;
+        let mut request = serde_json::to_string(&RpcRequest::new(1, method, params))?;
+        request.push('\n');
+        stream.write_all(request.as_bytes())?;
+
+        let mut line = String::new();
+        std::io::BufReader::new(stream).read_line(&mut line)?;
+        let response: RpcResponse = serde_json::from_str(&line)?;
+
+        response.into_result()
+    }
+}
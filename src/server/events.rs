@@ -0,0 +1,230 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::acl::Acl;
+use crate::server::bus::EventBus;
+use crate::server::limits::Limiter;
+use crate::server::metrics::Metrics;
+use crate::server::rpc::{RequestEnvelope, ResponseEnvelope, RPC_RESPONSE_EVENT};
+use crate::server::shutdown::Shutdown;
+use crate::utils::result::SnekcloudResult;
+use futures::Future;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use vented::event::Event;
+use vented::server::VentedServer;
+
+/// An event name bound to the type of its payload
+pub struct TypedEvent<T> {
+    name: &'static str,
+    payload: PhantomData<fn() -> T>,
+}
+
+/// A request name bound to the types of the request and response payloads
+pub struct TypedRequest<Req, Resp> {
+    name: &'static str,
+    payload: PhantomData<fn(Req) -> Resp>,
+}
+
+impl<T> TypedEvent<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            payload: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T: Serialize> TypedEvent<T> {
+    /// Creates the event with the given payload
+    pub fn event(&self, payload: &T) -> Event {
+        Event::with_payload(self.name, payload)
+    }
+}
+
+impl<Req, Resp> TypedRequest<Req, Resp> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            payload: PhantomData,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// A handler for incoming events.
+/// An event returned by the handler is sent back to the origin of the event.
+pub type EventHandler =
+    Box<dyn Fn(Event) -> Pin<Box<dyn Future<Output = Option<Event>>>> + Send + Sync>;
+
+/// Dispatches incoming events to the registered handlers
+pub trait EventTarget {
+    fn on(&mut self, event_name: &str, handler: EventHandler);
+}
+
+impl EventTarget for VentedServer {
+    fn on(&mut self, event_name: &str, handler: EventHandler) {
+        VentedServer::on(self, event_name, handler)
+    }
+}
+
+/// Used by modules to register handlers for incoming events
+pub struct EventRegistry<'a> {
+    server: &'a mut dyn EventTarget,
+    bus: EventBus,
+    metrics: Metrics,
+    acl: Acl,
+    limiter: Limiter,
+    shutdown: Option<Shutdown>,
+}
+
+impl<'a> EventRegistry<'a> {
+    pub fn new(
+        server: &'a mut dyn EventTarget,
+        bus: EventBus,
+        metrics: Metrics,
+        acl: Acl,
+        limiter: Limiter,
+    ) -> Self {
+        Self {
+            server,
+            bus,
+            metrics,
+            acl,
+            limiter,
+            shutdown: None,
+        }
+    }
+
+    /// Drops all incoming events once the shutdown has been triggered
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = Some(shutdown);
+
+        self
+    }
+
+    /// Returns the local event bus so that handlers can publish on it
+    pub fn bus(&self) -> EventBus {
+        self.bus.clone()
+    }
+
+    /// Returns the metrics so that handlers can record them
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Registers a handler for the event.
+    /// An event returned by the handler is sent back to the origin of the event.
+    /// Events from nodes that aren't allowed to send them or that exceed a limit are dropped.
+    pub fn on<F>(&mut self, event_name: &str, handler: F)
+    where
+        F: Fn(Event) -> Pin<Box<dyn Future<Output = Option<Event>>>> + Send + Sync + 'static,
+    {
+        let metrics = self.metrics.clone();
+        let acl = self.acl.clone();
+        let limiter = self.limiter.clone();
+        let shutdown = self.shutdown.clone();
+
+        self.server.on(
+            event_name,
+            Box::new(move |event| {
+                if shutdown.as_ref().is_some_and(Shutdown::is_triggered) {
+                    return Box::pin(async { None });
+                }
+                // rejected events count towards the limit so that untrusted nodes can't flood
+                if let Err(violation) = limiter.check(&event) {
+                    metrics.record_limited(
+                        event.origin.as_deref().unwrap_or("unknown"),
+                        violation.name(),
+                    );
+                    return Box::pin(async { None });
+                }
+                if !acl.check(&event) {
+                    metrics.record_rejected_event(&event.name);
+                    return Box::pin(async { None });
+                }
+                metrics.record_event(&event.name);
+                handler(event)
+            }),
+        )
+    }
+
+    /// Registers a handler for the typed event that gets the origin and the payload.
+    /// Events with a payload that can't be decoded are logged and dropped.
+    pub fn on_typed<T, F, Fut>(&mut self, event: &TypedEvent<T>, handler: F)
+    where
+        T: DeserializeOwned + 'static,
+        F: Fn(Option<String>, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<Event>> + 'static,
+    {
+        let handler = Arc::new(handler);
+        let name = event.name();
+
+        self.on(name, move |event| {
+            let handler = Arc::clone(&handler);
+
+            Box::pin(async move {
+                match event.get_payload::<T>() {
+                    Ok(payload) => handler(event.origin, payload).await,
+                    Err(e) => {
+                        log::error!("Received {} event with invalid payload: {}", name, e);
+                        None
+                    }
+                }
+            })
+        })
+    }
+
+    /// Registers a handler for requests sent with `RunContext::request`
+    /// that gets the origin and the payload of the request.
+    /// The result of the handler is sent back as the response to the request.
+    pub fn on_request<Req, Resp, F, Fut>(&mut self, request: &TypedRequest<Req, Resp>, handler: F)
+    where
+        Req: DeserializeOwned + 'static,
+        Resp: Serialize + 'static,
+        F: Fn(Option<String>, Req) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = SnekcloudResult<Resp>> + 'static,
+    {
+        let handler = Arc::new(handler);
+        let name = request.name();
+
+        self.on(name, move |event| {
+            let handler = Arc::clone(&handler);
+
+            Box::pin(async move {
+                let request = match event.get_payload::<RequestEnvelope<Req>>() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        log::error!("Received invalid {} request: {}", name, e);
+                        return None;
+                    }
+                };
+                let result = handler(event.origin, request.payload).await.map_err(|e| {
+                    log::debug!("Failed to handle {} request: {}", name, e);
+                    e.to_string()
+                });
+
+                Some(Event::with_payload(
+                    RPC_RESPONSE_EVENT,
+                    &ResponseEnvelope {
+                        request_id: request.request_id,
+                        result,
+                    },
+                ))
+            })
+        })
+    }
+}
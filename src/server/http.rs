@@ -0,0 +1,202 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use async_std::future;
+use async_std::io::BufReader;
+use async_std::net::{TcpListener, TcpStream};
+use async_std::prelude::*;
+use async_std::task;
+use futures::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The maximum size of the request line and headers
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// The time a client gets to send the request line and headers
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request received by the http listener
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+}
+
+/// The response to a http request
+#[derive(Clone, Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl HttpResponse {
+    pub fn new<S: ToString>(status: u16, content_type: &'static str, body: S) -> Self {
+        Self {
+            status,
+            content_type,
+            body: body.to_string(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404, "text/plain", "Not Found")
+    }
+
+    pub fn bad_request() -> Self {
+        Self::new(400, "text/plain", "Bad Request")
+    }
+
+    /// Serializes the response including the status line and headers
+    fn to_bytes(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.status,
+            reason_phrase(self.status),
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+/// Starts listening for http requests on the given address.
+/// Every connection is closed after a single request.
+pub async fn listen<F, Fut>(address: String, handler: F) -> SnekcloudResult<()>
+where
+    F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = HttpResponse> + Send + 'static,
+{
+    let listener = TcpListener::bind(&address).await?;
+    log::info!("HTTP listener listening on {}", address);
+    let handler = Arc::new(handler);
+
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+
+        while let Some(stream) = incoming.next().await {
+            match stream {
+                Ok(stream) => {
+                    let handler = Arc::clone(&handler);
+                    task::spawn(async move {
+                        if let Err(e) = handle_connection(stream, handler.as_ref()).await {
+                            log::debug!("HTTP connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => log::warn!("Failed to accept http connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Reads a single request from the stream and writes the response of the handler
+async fn handle_connection<F, Fut>(stream: TcpStream, handler: &F) -> SnekcloudResult<()>
+where
+    F: Fn(HttpRequest) -> Fut,
+    Fut: Future<Output = HttpResponse>,
+{
+    let mut writer = stream.clone();
+    let request = future::timeout(READ_TIMEOUT, read_request(stream))
+        .await
+        .map_err(|_| SnekcloudError::Timeout)??;
+    let response = match request {
+        Some(request) => handler(request).await,
+        None => HttpResponse::bad_request(),
+    };
+    writer.write_all(&response.to_bytes()).await?;
+
+    Ok(())
+}
+
+/// Reads the request line and skips the headers.
+/// Returns None if the request is malformed or its head exceeds the maximum size.
+async fn read_request(stream: TcpStream) -> SnekcloudResult<Option<HttpRequest>> {
+    let mut lines = BufReader::new(stream).take(MAX_HEAD_SIZE as u64).lines();
+    let request_line = match lines.next().await {
+        Some(line) => line?,
+        None => return Ok(None),
+    };
+    let mut complete = false;
+    while let Some(line) = lines.next().await {
+        if line?.is_empty() {
+            complete = true;
+            break;
+        }
+    }
+    if !complete {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+
+    match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => Ok(Some(HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+        })),
+        _ => Ok(None),
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::net::TcpListener;
+
+    async fn send_request(head: Vec<u8>) -> Option<HttpRequest> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        // the server may stop reading before everything was written
+        let _ = client.write_all(&head).await;
+        drop(client);
+
+        read_request(stream).await.unwrap()
+    }
+
+    #[test]
+    fn it_reads_the_request_line() {
+        let request = task::block_on(send_request(
+            b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n".to_vec(),
+        ))
+        .unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/metrics");
+    }
+
+    #[test]
+    fn it_rejects_oversized_heads() {
+        let mut head = b"GET /metrics HTTP/1.1\r\n".to_vec();
+        head.extend(b"X-Filler: ".iter().cycle().take(MAX_HEAD_SIZE));
+        head.extend(b"\r\n\r\n");
+
+        assert!(task::block_on(send_request(head)).is_none());
+    }
+
+    #[test]
+    fn it_rejects_incomplete_heads() {
+        assert!(task::block_on(send_request(b"GET /metrics HTTP/1.1\r\n".to_vec())).is_none());
+    }
+}
@@ -0,0 +1,187 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::utils::settings::LimitSettings;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+use vented::event::Event;
+
+/// The reason an event was dropped by the limiter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitViolation {
+    RateLimit,
+    PayloadSize,
+}
+
+impl LimitViolation {
+    /// Returns the name used as metric label
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RateLimit => "rate_limit",
+            Self::PayloadSize => "payload_size",
+        }
+    }
+}
+
+impl fmt::Display for LimitViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RateLimit => write!(f, "rate limit exceeded"),
+            Self::PayloadSize => write!(f, "payload too large"),
+        }
+    }
+}
+
+/// Refills tokens continuously up to the burst size
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+    limited: bool,
+}
+
+impl TokenBucket {
+    fn new(burst: u32, now: Instant) -> Self {
+        Self {
+            tokens: burst as f64,
+            updated: now,
+            limited: false,
+        }
+    }
+
+    fn try_take(&mut self, rate: f64, burst: u32, now: Instant) -> bool {
+        let refill = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refill).min(burst as f64);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Limits the rate and size of the events each node sends
+#[derive(Clone)]
+pub struct Limiter {
+    settings: Arc<RwLock<LimitSettings>>,
+    buckets: Arc<Mutex<HashMap<String, TokenBucket>>>,
+}
+
+impl Limiter {
+    pub fn new(settings: LimitSettings) -> Self {
+        Self {
+            settings: Arc::new(RwLock::new(settings)),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Replaces the limits
+    pub fn update(&self, settings: LimitSettings) {
+        *self.settings.write() = settings;
+    }
+
+    /// Takes a token from the bucket of the sender and checks the size of the payload.
+    /// Only the first event that exceeds the rate limit after accepted events is logged
+    /// so that a flood doesn't flood the log as well.
+    /// The event has already been read and decoded by the transport at this point,
+    /// so the payload limit protects the handlers but not the memory used for decoding.
+    pub fn check(&self, event: &Event) -> Result<(), LimitViolation> {
+        let settings = self.settings.read();
+        let origin = event.origin.as_deref().unwrap_or("unknown");
+        {
+            let now = Instant::now();
+            let mut buckets = self.buckets.lock();
+            let bucket = buckets
+                .entry(origin.to_string())
+                .or_insert_with(|| TokenBucket::new(settings.burst, now));
+
+            if !bucket.try_take(settings.events_per_second, settings.burst, now) {
+                if !bucket.limited {
+                    log::warn!(
+                        "Node {} exceeded the rate limit. Dropping events until it slows down",
+                        origin
+                    );
+                    bucket.limited = true;
+                }
+                return Err(LimitViolation::RateLimit);
+            }
+            if bucket.limited {
+                log::info!("Node {} is below the rate limit again", origin);
+                bucket.limited = false;
+            }
+        }
+        let limit = settings.payload_limit(&event.name);
+        if event.payload.len() > limit {
+            log::warn!(
+                "Dropping {} event from {} with {} bytes of payload. The limit is {} bytes",
+                event.name,
+                origin,
+                event.payload.len(),
+                limit
+            );
+            return Err(LimitViolation::PayloadSize);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_allows_bursts_up_to_the_burst_size() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(3, start);
+
+        for _ in 0..3 {
+            assert!(bucket.try_take(1.0, 3, start));
+        }
+        assert!(!bucket.try_take(1.0, 3, start));
+    }
+
+    #[test]
+    fn it_refills_with_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1, start);
+        assert!(bucket.try_take(10.0, 1, start));
+
+        assert!(!bucket.try_take(10.0, 1, start + Duration::from_millis(50)));
+        assert!(bucket.try_take(10.0, 1, start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(10.0, 1, start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn it_refills_at_most_the_burst_size() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2, start);
+        let later = start + Duration::from_secs(60);
+
+        assert!(bucket.try_take(10.0, 2, later));
+        assert!(bucket.try_take(10.0, 2, later));
+        assert!(!bucket.try_take(10.0, 2, later));
+    }
+
+    #[test]
+    fn it_drops_events_with_large_payloads() {
+        let limiter = Limiter::new(LimitSettings {
+            max_payload_bytes: 4,
+            ..LimitSettings::default()
+        });
+        let mut event = Event::with_payload("event", &"payload".to_string());
+        event.origin = Some("node".to_string());
+
+        assert_eq!(limiter.check(&event), Err(LimitViolation::PayloadSize));
+        assert_eq!(limiter.check(&Event::new("event")), Ok(()));
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::supervisor::ModuleStatus;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+use vented::server::data::{Node, NodeState};
+
+/// The content type of the prometheus text format
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// The upper bounds of the heartbeat latency buckets in seconds
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counts observations in the latency buckets
+#[derive(Clone, Debug)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricValues {
+    emits_sent: BTreeMap<String, u64>,
+    emits_failed: BTreeMap<String, u64>,
+    events_received: BTreeMap<String, u64>,
+    events_rejected: BTreeMap<String, u64>,
+    events_limited: BTreeMap<(String, &'static str), u64>,
+    heartbeat_latency: BTreeMap<String, Histogram>,
+}
+
+/// Returns the number of invocations that wait to be emitted
+type QueueDepth = Arc<dyn Fn() -> usize + Send + Sync>;
+
+/// The metrics collected by the server and its modules
+#[derive(Clone, Default)]
+pub struct Metrics {
+    values: Arc<Mutex<MetricValues>>,
+    queue_depth: Arc<Mutex<Option<QueueDepth>>>,
+}
+
+impl Metrics {
+    /// Counts an event emitted to the node
+    pub fn record_emit(&self, node: &str, success: bool) {
+        let mut values = self.values.lock();
+        let counters = if success {
+            &mut values.emits_sent
+        } else {
+            &mut values.emits_failed
+        };
+        *counters.entry(node.to_string()).or_default() += 1;
+    }
+
+    /// Counts an incoming event
+    pub fn record_event(&self, name: &str) {
+        *self
+            .values
+            .lock()
+            .events_received
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts an incoming event that was rejected by the acl
+    pub fn record_rejected_event(&self, name: &str) {
+        *self
+            .values
+            .lock()
+            .events_rejected
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    /// Counts an incoming event that was dropped because the node exceeded a limit
+    pub fn record_limited(&self, node: &str, reason: &'static str) {
+        *self
+            .values
+            .lock()
+            .events_limited
+            .entry((node.to_string(), reason))
+            .or_default() += 1;
+    }
+
+    /// Records the round trip time of a heartbeat to the node
+    pub fn record_heartbeat_latency(&self, node: &str, latency: Duration) {
+        self.values
+            .lock()
+            .heartbeat_latency
+            .entry(node.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Sets the function that is sampled for the invocation queue depth when rendering
+    pub fn set_queue_depth<F>(&self, queue_depth: F)
+    where
+        F: Fn() -> usize + Send + Sync + 'static,
+    {
+        self.queue_depth.lock().replace(Arc::new(queue_depth));
+    }
+
+    /// Renders the metrics in the prometheus text format
+    pub fn render(
+        &self,
+        nodes: &[(Node, NodeState)],
+        modules: &HashMap<String, ModuleStatus>,
+    ) -> String {
+        let queue_depth = self.queue_depth.lock().as_ref().map_or(0, |depth| depth());
+        let values = self.values.lock();
+        let mut output = String::new();
+
+        write_header(
+            &mut output,
+            "snekcloud_emits_sent_total",
+            "Events delivered to a node",
+            "counter",
+        );
+        for (node, count) in &values.emits_sent {
+            write_sample(
+                &mut output,
+                "snekcloud_emits_sent_total",
+                &[("node", node)],
+                count,
+            );
+        }
+        write_header(
+            &mut output,
+            "snekcloud_emits_failed_total",
+            "Events that couldn't be delivered to a node",
+            "counter",
+        );
+        for (node, count) in &values.emits_failed {
+            write_sample(
+                &mut output,
+                "snekcloud_emits_failed_total",
+                &[("node", node)],
+                count,
+            );
+        }
+        write_header(
+            &mut output,
+            "snekcloud_invocation_queue_depth",
+            "Events waiting to be emitted",
+            "gauge",
+        );
+        write_sample(
+            &mut output,
+            "snekcloud_invocation_queue_depth",
+            &[],
+            queue_depth,
+        );
+        write_header(
+            &mut output,
+            "snekcloud_events_received_total",
+            "Events received from other nodes",
+            "counter",
+        );
+        for (event, count) in &values.events_received {
+            write_sample(
+                &mut output,
+                "snekcloud_events_received_total",
+                &[("event", event)],
+                count,
+            );
+        }
+        write_header(
+            &mut output,
+            "snekcloud_events_rejected_total",
+            "Events dropped because the sender isn't allowed to send them",
+            "counter",
+        );
+        for (event, count) in &values.events_rejected {
+            write_sample(
+                &mut output,
+                "snekcloud_events_rejected_total",
+                &[("event", event)],
+                count,
+            );
+        }
+        write_header(
+            &mut output,
+            "snekcloud_events_limited_total",
+            "Events dropped because the sender exceeded a limit",
+            "counter",
+        );
+        for ((node, reason), count) in &values.events_limited {
+            write_sample(
+                &mut output,
+                "snekcloud_events_limited_total",
+                &[("node", node), ("reason", reason)],
+                count,
+            );
+        }
+        write_header(
+            &mut output,
+            "snekcloud_heartbeat_latency_seconds",
+            "Round trip time of heartbeats",
+            "histogram",
+        );
+        for (node, histogram) in &values.heartbeat_latency {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.buckets) {
+                write_sample(
+                    &mut output,
+                    "snekcloud_heartbeat_latency_seconds_bucket",
+                    &[("node", node), ("le", &bound.to_string())],
+                    count,
+                );
+            }
+            write_sample(
+                &mut output,
+                "snekcloud_heartbeat_latency_seconds_bucket",
+                &[("node", node), ("le", "+Inf")],
+                histogram.count,
+            );
+            write_sample(
+                &mut output,
+                "snekcloud_heartbeat_latency_seconds_sum",
+                &[("node", node)],
+                histogram.sum,
+            );
+            write_sample(
+                &mut output,
+                "snekcloud_heartbeat_latency_seconds_count",
+                &[("node", node)],
+                histogram.count,
+            );
+        }
+
+        let alive = nodes
+            .iter()
+            .filter(|(_, state)| matches!(state, NodeState::Alive(_)))
+            .count();
+        let trusted = nodes.iter().filter(|(node, _)| node.trusted).count();
+        write_header(&mut output, "snekcloud_nodes_known", "Known nodes", "gauge");
+        write_sample(&mut output, "snekcloud_nodes_known", &[], nodes.len());
+        write_header(
+            &mut output,
+            "snekcloud_nodes_alive",
+            "Reachable nodes",
+            "gauge",
+        );
+        write_sample(&mut output, "snekcloud_nodes_alive", &[], alive);
+        write_header(
+            &mut output,
+            "snekcloud_nodes_trusted",
+            "Trusted nodes",
+            "gauge",
+        );
+        write_sample(&mut output, "snekcloud_nodes_trusted", &[], trusted);
+
+        write_header(
+            &mut output,
+            "snekcloud_module_restarts_total",
+            "Restarts of a module by the supervisor",
+            "counter",
+        );
+        let modules: BTreeMap<&String, &ModuleStatus> = modules.iter().collect();
+        for (module, status) in modules {
+            write_sample(
+                &mut output,
+                "snekcloud_module_restarts_total",
+                &[("module", module)],
+                status.restarts,
+            );
+        }
+
+        output
+    }
+}
+
+fn write_header(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn write_sample<V: ToString>(output: &mut String, name: &str, labels: &[(&str, &str)], value: V) {
+    output.push_str(name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect();
+        let _ = write!(output, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(output, " {}", value.to_string());
+}
+
+/// Escapes a label value as required by the text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -4,35 +4,162 @@
  * See LICENSE for more information
  */
 
+use crate::data::storage::Storage;
 use crate::modules::Module;
+use crate::server::acl::Acl;
+use crate::server::bus::EventBus;
+use crate::server::control::ControlMethods;
+use crate::server::events::EventRegistry;
+use crate::server::http::HttpResponse;
+use crate::server::limits::Limiter;
+use crate::server::metrics::{Metrics, METRICS_CONTENT_TYPE};
+use crate::server::node_watcher::NodeWatcher;
+use crate::server::notify::{report_status, Notifier, Watchdog};
+use crate::server::reload::handle_reloads;
+use crate::server::rpc::{PendingRequests, RPC_RESPONSE_EVENT};
+use crate::server::shutdown::Shutdown;
+use crate::server::signals::handle_signals;
+use crate::server::supervisor::{supervise, ModuleStates, ModuleStatus};
 use crate::server::tick_context::{EventInvocation, RunContext};
+use crate::utils::keys::{armor_public_key, key_fingerprint};
 use crate::utils::result::{SnekcloudError, SnekcloudResult};
-use crate::utils::settings::get_settings;
+use crate::utils::settings::Settings;
 
+use async_std::future;
+use async_std::net::TcpStream;
 use async_std::sync::{channel, Receiver};
-use async_std::task;
+use async_std::task::{self, JoinHandle};
+use futures::future::{join_all, select, Either};
+use futures::pin_mut;
+use parking_lot::RwLock;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use std::collections::HashMap;
+use std::fs;
 use std::mem;
-use vented::server::data::Node;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vented::event::Event;
+use vented::server::data::{Node, NodeState};
 use vented::server::VentedServer;
 use vented::stream::SecretKey;
 
+pub mod acl;
+pub mod api;
+pub mod bus;
+pub mod clock;
+pub mod control;
+pub mod events;
+pub mod http;
+pub mod limits;
+pub mod metrics;
+pub mod node_watcher;
+pub mod notify;
+pub mod reload;
+pub mod rpc;
+pub mod shutdown;
+pub mod signals;
+pub mod supervisor;
 pub mod tick_context;
 
+const CONTROL_EMIT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long to wait for the listeners to accept connections before startup is considered failed
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct SnekcloudServer {
     inner: VentedServer,
     listen_addresses: Vec<String>,
+    control_socket: Option<PathBuf>,
     modules: HashMap<String, Box<dyn Module + Send + Sync>>,
+    shutdown_timeout: Duration,
+    in_flight: Arc<AtomicUsize>,
+    pending_requests: PendingRequests,
+    bus: EventBus,
+    storage: Storage,
+    settings: Settings,
+    trusted_nodes: Arc<RwLock<Vec<String>>>,
+    metrics: Metrics,
+    acl: Acl,
+    limiter: Limiter,
+    shutdown: Shutdown,
+}
+
+#[derive(Deserialize)]
+struct EmitParams {
+    node: String,
+    event: String,
+    #[serde(default)]
+    payload: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct BroadcastParams {
+    event: String,
+    #[serde(default)]
+    payload: Option<Value>,
 }
 
 impl SnekcloudServer {
-    /// Creates a new snekcloud server with the provided keys and number of threads
-    pub fn new(id: String, private_key: SecretKey, keys: Vec<Node>) -> Self {
-        Self {
-            inner: VentedServer::new(id, private_key, keys, get_settings().timeouts()),
+    /// Creates a new snekcloud server with the provided settings and keys
+    pub fn new(
+        settings: Settings,
+        private_key: SecretKey,
+        keys: Vec<Node>,
+    ) -> SnekcloudResult<Self> {
+        let storage = Storage::open(&settings.database_path)?;
+        let mut inner = VentedServer::new(
+            settings.node_id.clone(),
+            private_key,
+            keys,
+            settings.timeouts(),
+        );
+        let pending_requests = PendingRequests::default();
+        let bus = EventBus::default();
+        let metrics = Metrics::default();
+        let acl = Acl::new(settings.acl.clone(), inner.nodes_ref());
+        let limiter = Limiter::new(settings.limits.clone());
+        let shutdown = Shutdown::new();
+        EventRegistry::new(
+            &mut inner,
+            bus.clone(),
+            metrics.clone(),
+            acl.clone(),
+            limiter.clone(),
+        )
+        .with_shutdown(shutdown.clone())
+        .on(RPC_RESPONSE_EVENT, {
+            let pending_requests = pending_requests.clone();
+            move |event| {
+                pending_requests.resolve(event);
+                Box::pin(async { None })
+            }
+        });
+
+        Ok(Self {
+            inner,
             listen_addresses: Vec::new(),
+            control_socket: None,
             modules: HashMap::new(),
-        }
+            shutdown_timeout: settings.shutdown_timeout(),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            pending_requests,
+            bus,
+            storage,
+            trusted_nodes: Arc::new(RwLock::new(settings.trusted_nodes.clone())),
+            settings,
+            metrics,
+            acl,
+            limiter,
+            shutdown,
+        })
+    }
+
+    /// Returns a handle that stops the running server when triggered
+    #[cfg(test)]
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown.clone()
     }
 
     /// Adds an address the server should listen on
@@ -40,51 +167,373 @@ impl SnekcloudServer {
         self.listen_addresses.push(address);
     }
 
+    /// Sets the path of the unix socket the control api is served on
+    pub fn set_control_socket(&mut self, path: PathBuf) {
+        self.control_socket = Some(path);
+    }
+
     /// Starts listening on all addresses and runs the module tick loop
     pub fn run(&mut self) -> SnekcloudResult<()> {
         for address in &self.listen_addresses {
             self.inner.listen(address.clone())
         }
 
-        let mut modules = mem::take(&mut self.modules).into_iter();
+        let modules = mem::take(&mut self.modules);
         let (tx, rx) = channel(10);
-        let tick_context = RunContext::new(self.inner.node_id(), tx, self.inner.nodes_ref());
+        self.metrics.set_queue_depth({
+            let rx = rx.clone();
+            move || rx.len()
+        });
+        let control_methods = ControlMethods::default();
+        let module_states = ModuleStates::default();
+        let tick_context = RunContext::new(
+            self.inner.node_id(),
+            tx,
+            self.inner.nodes_ref(),
+            control_methods.clone(),
+            module_states.clone(),
+            self.pending_requests.clone(),
+            self.bus.clone(),
+            self.storage.clone(),
+            self.metrics.clone(),
+        );
+        Self::register_control_methods(&tick_context);
 
-        while let Some((name, mut module)) = modules.next() {
-            let tick_context = RunContext::clone(&tick_context);
-            task::spawn(async move {
-                if let Err(e) = module.run(RunContext::clone(&tick_context)).await {
-                    log::error!("Error when ticking module {}: {}", name, e);
-                }
-            });
+        if let Some(path) = &self.control_socket {
+            self.listen_control(path.clone(), control_methods.clone())?;
+        }
+        if let Some(address) = &self.settings.api_address {
+            Self::listen_api(address.clone(), control_methods)?;
+        }
+        if let Some(address) = &self.settings.metrics_address {
+            Self::listen_metrics(address.clone(), tick_context.clone())?;
         }
 
-        task::block_on(self.handle_invocations(rx));
+        let shutdown = self.shutdown.clone();
+        let (reload_tx, reload_rx) = channel(1);
+        handle_signals(shutdown.clone(), reload_tx)?;
+
+        let mut reconfigure_senders = Vec::new();
+        let module_handles: Vec<JoinHandle<()>> = modules
+            .into_iter()
+            .map(|(name, module)| {
+                let (reconfigure_tx, reconfigure_rx) = channel(1);
+                reconfigure_senders.push((name.clone(), reconfigure_tx));
+                task::spawn(supervise(
+                    name,
+                    module,
+                    RunContext::clone(&tick_context),
+                    module_states.clone(),
+                    shutdown.clone(),
+                    reconfigure_rx,
+                ))
+            })
+            .collect();
+        task::spawn(handle_reloads(
+            self.settings.clone(),
+            reload_rx,
+            self.inner.nodes_ref(),
+            Arc::clone(&self.trusted_nodes),
+            self.acl.clone(),
+            self.limiter.clone(),
+            self.bus.clone(),
+            reconfigure_senders,
+        ));
+        task::spawn(
+            NodeWatcher::new(
+                self.settings.node_data_dir.clone(),
+                self.inner.node_id(),
+                self.inner.nodes_ref(),
+                Arc::clone(&self.trusted_nodes),
+                self.bus.clone(),
+            )
+            .run(),
+        );
+        let notifier = Notifier::from_env();
+        if let Some(notifier) = &notifier {
+            if task::block_on(self.wait_for_listeners()) {
+                notifier.ready();
+            }
+            task::spawn(report_status(notifier.clone(), tick_context.clone()));
+        }
+        let watchdog = notifier.clone().and_then(Watchdog::from_env);
+
+        task::block_on(async {
+            self.handle_invocations(rx, shutdown, watchdog).await;
+            if let Some(notifier) = &notifier {
+                notifier.stopping();
+            }
+            let deadline = Instant::now() + self.shutdown_timeout;
+            self.cancel_invocations(deadline).await;
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if future::timeout(remaining, join_all(module_handles))
+                .await
+                .is_err()
+            {
+                log::warn!("Not all modules stopped in time");
+            }
+        });
+        if let Some(path) = &self.control_socket {
+            let _ = fs::remove_file(path);
+        }
+        // the vented listeners can't be closed so they accept connections until the process
+        // exits, but the events they receive are dropped since the shutdown was triggered
+        log::info!("Shutdown complete");
 
         Ok(())
     }
 
-    /// Handles invocations
-    async fn handle_invocations(&self, rx: Receiver<EventInvocation>) {
-        while let Ok(mut invocation) = rx.recv().await {
+    /// Waits until every listen address accepts connections.
+    /// The listeners are bound in the background without reporting when they're ready.
+    async fn wait_for_listeners(&self) -> bool {
+        for address in &self.listen_addresses {
+            let start = Instant::now();
+
+            while TcpStream::connect(address).await.is_err() {
+                if start.elapsed() > LISTEN_TIMEOUT {
+                    log::error!("The listener on {} didn't start", address);
+                    return false;
+                }
+                task::sleep(Duration::from_millis(100)).await;
+            }
+        }
+
+        true
+    }
+
+    /// Handles invocations until the server shuts down.
+    /// The watchdog is only pinged while the loop keeps running.
+    async fn handle_invocations(
+        &self,
+        rx: Receiver<EventInvocation>,
+        shutdown: Shutdown,
+        mut watchdog: Option<Watchdog>,
+    ) {
+        loop {
+            if let Some(watchdog) = &mut watchdog {
+                watchdog.ping_if_due();
+            }
+            let invocation = {
+                let next = rx.recv();
+                let stopped = shutdown.wait();
+                pin_mut!(next);
+                pin_mut!(stopped);
+                let next = select(next, stopped);
+
+                let result = match &watchdog {
+                    Some(watchdog) => match future::timeout(watchdog.until_due(), next).await {
+                        Ok(result) => result,
+                        Err(_) => continue,
+                    },
+                    None => next.await,
+                };
+                match result {
+                    Either::Left((Ok(invocation), _)) => invocation,
+                    _ => break,
+                }
+            };
             let inner = self.inner.clone();
-            task::spawn(async move {
-                let result = task::block_on(inner.emit(invocation.target_node, invocation.event));
-                invocation
-                    .result
-                    .result(result.map_err(SnekcloudError::from));
+            let metrics = self.metrics.clone();
+            let in_flight = Arc::clone(&self.in_flight);
+            let shutdown = shutdown.clone();
+            in_flight.fetch_add(1, Ordering::SeqCst);
+
+            // the emit future isn't Send so it runs on the executor of the server thread
+            // which is driven while the invocations are handled and cancelled
+            task::spawn_local(async move {
+                let target_node = invocation.target_node.clone();
+                let emit = inner.emit(invocation.target_node, invocation.event);
+                let stopped = shutdown.wait();
+                pin_mut!(emit);
+                pin_mut!(stopped);
+                let result = match select(emit, stopped).await {
+                    Either::Left((result, _)) => {
+                        metrics.record_emit(&target_node, result.is_ok());
+                        result.map_err(SnekcloudError::from)
+                    }
+                    Either::Right(_) => Err(SnekcloudError::Cancelled),
+                };
+                // the receiver might not be interested in the result
+                let _ = invocation.result.send(result);
+                in_flight.fetch_sub(1, Ordering::SeqCst);
             });
         }
     }
 
+    /// Waits until the emits that were still in flight when the shutdown started
+    /// are cancelled or the deadline is reached
+    async fn cancel_invocations(&self, deadline: Instant) {
+        let undelivered = self.in_flight.load(Ordering::SeqCst);
+        if undelivered > 0 {
+            log::warn!("Cancelling {} undelivered events", undelivered);
+        }
+        while self.in_flight.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
     /// Registers a module on the server
     pub fn register_module(
         &mut self,
-        mut module: impl Module + Send + Sync,
+        mut module: Box<dyn Module + Send + Sync>,
     ) -> SnekcloudResult<()> {
-        module.init(&mut self.inner)?;
-        self.modules.insert(module.name(), module.boxed());
+        module.init(
+            &mut EventRegistry::new(
+                &mut self.inner,
+                self.bus.clone(),
+                self.metrics.clone(),
+                self.acl.clone(),
+                self.limiter.clone(),
+            )
+            .with_shutdown(self.shutdown.clone()),
+        )?;
+        self.modules.insert(module.name(), module);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn listen_control(&self, path: PathBuf, methods: ControlMethods) -> SnekcloudResult<()> {
+        task::block_on(control::listen(path, methods))
+    }
+
+    #[cfg(not(unix))]
+    fn listen_control(&self, _path: PathBuf, _methods: ControlMethods) -> SnekcloudResult<()> {
+        log::warn!("The control socket is only supported on unix systems");
 
         Ok(())
     }
+
+    /// Serves the prometheus metrics on the given address
+    fn listen_metrics(address: String, context: RunContext) -> SnekcloudResult<()> {
+        task::block_on(http::listen(address, move |request| {
+            let context = context.clone();
+            async move {
+                if request.method != "GET" || request.path != "/metrics" {
+                    return HttpResponse::not_found();
+                }
+                let body = context
+                    .metrics()
+                    .render(&context.node_states(), &context.module_states());
+
+                HttpResponse::new(200, METRICS_CONTENT_TYPE, body)
+            }
+        }))
+    }
+
+    /// Serves the read-only json api on the given address
+    fn listen_api(address: String, methods: ControlMethods) -> SnekcloudResult<()> {
+        task::block_on(http::listen(address, move |request| {
+            let methods = methods.clone();
+            async move { api::handle_request(&methods, request).await }
+        }))
+    }
+
+    /// Registers the control methods provided by the server itself
+    fn register_control_methods(context: &RunContext) {
+        context.register_control_method("nodes.list", {
+            let context = context.clone();
+            move |_| {
+                let nodes: Vec<Value> = context
+                    .node_states()
+                    .into_iter()
+                    .map(|(node, state)| {
+                        json!({
+                            "id": node.id,
+                            "addresses": node.addresses,
+                            "trusted": node.trusted,
+                            "public_key": armor_public_key(node.public_key),
+                            "fingerprint": key_fingerprint(&node.public_key),
+                            "state": node_state_name(&state),
+                        })
+                    })
+                    .collect();
+
+                async move { Ok(Value::from(nodes)) }
+            }
+        });
+        context.register_control_method("modules.list", {
+            let context = context.clone();
+            move |_| {
+                let mut modules: Vec<(String, ModuleStatus)> =
+                    context.module_states().into_iter().collect();
+                modules.sort_by(|(a, _), (b, _)| a.cmp(b));
+                let modules: Vec<Value> = modules
+                    .into_iter()
+                    .map(|(name, status)| {
+                        json!({
+                            "name": name,
+                            "state": status.state,
+                            "restarts": status.restarts,
+                            "last_error": status.last_error,
+                        })
+                    })
+                    .collect();
+
+                async move { Ok(Value::from(modules)) }
+            }
+        });
+        context.register_control_method("event.emit", {
+            let context = context.clone();
+            move |params| {
+                let mut context = context.clone();
+                async move {
+                    let params: EmitParams = serde_json::from_value(params)?;
+                    let event = match params.payload {
+                        Some(payload) => Event::with_payload(params.event, &payload),
+                        None => Event::new(params.event),
+                    };
+                    context
+                        .emit(params.node, event)
+                        .await
+                        .wait_with_timeout(CONTROL_EMIT_TIMEOUT)
+                        .await?;
+
+                    Ok(json!({ "delivered": true }))
+                }
+            }
+        });
+        context.register_control_method("event.broadcast", {
+            let context = context.clone();
+            move |params| {
+                let context = context.clone();
+                async move {
+                    let params: BroadcastParams = serde_json::from_value(params)?;
+                    let event = match params.payload {
+                        Some(payload) => Event::with_payload(params.event, &payload),
+                        None => Event::new(params.event),
+                    };
+                    let result = context.broadcast(event, CONTROL_EMIT_TIMEOUT).await;
+                    let nodes: HashMap<&String, Value> = result
+                        .results()
+                        .iter()
+                        .map(|(node, result)| {
+                            let value = match result {
+                                Ok(_) => json!({ "delivered": true }),
+                                Err(e) => json!({ "delivered": false, "error": e.to_string() }),
+                            };
+                            (node, value)
+                        })
+                        .collect();
+
+                    Ok(json!({
+                        "delivered": result.succeeded(),
+                        "failed": result.failed(),
+                        "timed_out": result.timed_out(),
+                        "nodes": nodes,
+                    }))
+                }
+            }
+        });
+    }
+}
+
+/// Returns the name of the node state for display
+pub fn node_state_name(state: &NodeState) -> &'static str {
+    match state {
+        NodeState::Alive(_) => "alive",
+        NodeState::Dead(_) => "dead",
+        NodeState::Unknown => "unknown",
+    }
 }
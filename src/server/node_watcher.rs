@@ -0,0 +1,178 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::data::node_data::NodeData;
+use crate::modules::topics::{
+    NodeDiscovered, NodeRemoved, RemovalReason, NODE_DISCOVERED, NODE_REMOVED,
+};
+use crate::server::bus::EventBus;
+use crate::server::reload::{apply_trusted_nodes, publish_untrusted};
+use crate::utils::result::SnekcloudResult;
+use async_std::task;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use vented::server::data::{Node, NodeData as VentedNodeData};
+
+/// How often the node data directory is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Applies changes to the files in the node data directory to the nodes known by the server
+pub struct NodeWatcher {
+    path: PathBuf,
+    own_id: String,
+    nodes: Arc<Mutex<HashMap<String, VentedNodeData>>>,
+    trusted_nodes: Arc<RwLock<Vec<String>>>,
+    bus: EventBus,
+    modified: HashMap<PathBuf, SystemTime>,
+    file_nodes: HashMap<String, NodeData>,
+}
+
+impl NodeWatcher {
+    /// Creates the watcher with the current content of the directory
+    /// which is expected to be the one the server was started with
+    pub fn new(
+        path: PathBuf,
+        own_id: String,
+        nodes: Arc<Mutex<HashMap<String, VentedNodeData>>>,
+        trusted_nodes: Arc<RwLock<Vec<String>>>,
+        bus: EventBus,
+    ) -> Self {
+        let mut watcher = Self {
+            path,
+            own_id,
+            nodes,
+            trusted_nodes,
+            bus,
+            modified: HashMap::new(),
+            file_nodes: HashMap::new(),
+        };
+        match watcher.scan() {
+            Ok(modified) => watcher.modified = modified,
+            Err(e) => log::error!("Failed to scan the node data directory: {}", e),
+        }
+        match watcher.read_nodes() {
+            Ok(file_nodes) => watcher.file_nodes = file_nodes,
+            Err(e) => log::error!("Failed to read the node data directory: {}", e),
+        }
+
+        watcher
+    }
+
+    /// Checks the directory for changes until the server stops
+    pub async fn run(mut self) {
+        loop {
+            task::sleep(POLL_INTERVAL).await;
+            if let Err(e) = self.update() {
+                log::error!(
+                    "Failed to update the nodes from the node data directory: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    /// Applies the changes to the node files since the last update
+    fn update(&mut self) -> SnekcloudResult<()> {
+        let modified = self.scan()?;
+        if modified == self.modified {
+            return Ok(());
+        }
+        self.modified = modified;
+        let file_nodes = self.read_nodes()?;
+        let trusted_nodes = self.trusted_nodes.read().clone();
+        let mut discovered = Vec::new();
+        let mut removed = Vec::new();
+        {
+            let mut nodes = self.nodes.lock();
+
+            for id in self.file_nodes.keys() {
+                if !file_nodes.contains_key(id) && nodes.remove(id).is_some() {
+                    log::info!("Removed node {}", id);
+                    removed.push(id.clone());
+                }
+            }
+            for (id, data) in &file_nodes {
+                if self.file_nodes.get(id) == Some(data) {
+                    continue;
+                }
+                if let Some(node) = nodes.get_mut(id) {
+                    let node = node.node_mut();
+                    node.addresses = data.addresses.clone();
+                    node.public_key = data.public_key();
+                } else {
+                    nodes.insert(
+                        id.clone(),
+                        VentedNodeData::from(Node {
+                            id: id.clone(),
+                            public_key: data.public_key(),
+                            addresses: data.addresses.clone(),
+                            trusted: trusted_nodes.contains(id),
+                        }),
+                    );
+                }
+                // nodes received in node lists can already be known by the server
+                // but are only announced once their file exists
+                if self.file_nodes.contains_key(id) {
+                    log::info!("Updated node {}", id);
+                } else {
+                    log::info!("Added node {}", id);
+                    discovered.push(id.clone());
+                }
+            }
+        }
+        let untrusted = apply_trusted_nodes(&self.nodes, &trusted_nodes);
+        publish_untrusted(&self.bus, untrusted);
+        for node in removed {
+            self.bus.publish(
+                &NODE_REMOVED,
+                NodeRemoved {
+                    node,
+                    reason: RemovalReason::Deleted,
+                },
+            );
+        }
+        for node in discovered {
+            self.bus.publish(&NODE_DISCOVERED, NodeDiscovered { node });
+        }
+        self.file_nodes = file_nodes;
+
+        Ok(())
+    }
+
+    /// Returns the modification times of all files in the directory
+    fn scan(&self) -> SnekcloudResult<HashMap<PathBuf, SystemTime>> {
+        let mut modified = HashMap::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            modified.insert(entry.path(), entry.metadata()?.modified()?);
+        }
+
+        Ok(modified)
+    }
+
+    /// Reads all valid node files except the one of the local node
+    fn read_nodes(&self) -> SnekcloudResult<HashMap<String, NodeData>> {
+        let nodes = NodeData::read_dir(&self.path)?
+            .into_iter()
+            .filter_map(|(path, data)| match data {
+                Ok(data) => Some(data),
+                Err(e) => {
+                    log::warn!("Skipping invalid node file {:?}: {}", path, e);
+                    None
+                }
+            })
+            .filter(|data| data.id != self.own_id)
+            .map(|data| (data.id.clone(), data))
+            .collect();
+
+        Ok(nodes)
+    }
+}
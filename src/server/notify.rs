@@ -0,0 +1,300 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::tick_context::RunContext;
+use async_std::task;
+use std::env;
+use std::io;
+use std::process;
+use std::time::{Duration, Instant};
+use vented::server::data::NodeState;
+
+/// The environment variable systemd sets to the path of the notify socket
+const NOTIFY_SOCKET_ENV: &str = "NOTIFY_SOCKET";
+/// The environment variable systemd sets to the watchdog timeout in microseconds
+const WATCHDOG_USEC_ENV: &str = "WATCHDOG_USEC";
+/// The environment variable systemd sets to the pid that should send watchdog pings
+const WATCHDOG_PID_ENV: &str = "WATCHDOG_PID";
+
+/// How often the status is checked for changes
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sends state changes to the service manager as described in sd_notify(3)
+#[derive(Clone)]
+pub struct Notifier {
+    #[cfg(unix)]
+    socket: std::sync::Arc<unix::NotifySocket>,
+}
+
+impl Notifier {
+    /// Creates the notifier for the socket in the NOTIFY_SOCKET environment variable.
+    /// Returns None when the server isn't run by a service manager.
+    pub fn from_env() -> Option<Self> {
+        let address = env::var(NOTIFY_SOCKET_ENV).ok()?;
+
+        match Self::new(&address) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                log::warn!("Failed to open the notify socket {}: {}", address, e);
+                None
+            }
+        }
+    }
+
+    /// Creates the notifier for the given socket path.
+    /// Paths starting with `@` refer to abstract sockets.
+    #[cfg(unix)]
+    pub fn new(address: &str) -> io::Result<Self> {
+        Ok(Self {
+            socket: std::sync::Arc::new(unix::NotifySocket::new(address)?),
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(_address: &str) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Notify sockets are only supported on unix systems",
+        ))
+    }
+
+    /// Sends the newline separated state assignments
+    #[cfg(unix)]
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send(state.as_bytes())
+    }
+
+    #[cfg(not(unix))]
+    pub fn notify(&self, _state: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Tells the service manager that the server finished starting
+    pub fn ready(&self) {
+        self.send("READY=1")
+    }
+
+    /// Tells the service manager that the server is shutting down
+    pub fn stopping(&self) {
+        self.send("STOPPING=1")
+    }
+
+    /// Sets the status line shown by the service manager
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={}", status))
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.notify(state) {
+            log::warn!("Failed to send {} to the service manager: {}", state, e);
+        }
+    }
+}
+
+/// Sends watchdog pings while the loop calling it keeps making progress
+pub struct Watchdog {
+    notifier: Notifier,
+    interval: Duration,
+    last_ping: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Creates the watchdog if the service manager expects pings from this process
+    pub fn from_env(notifier: Notifier) -> Option<Self> {
+        let timeout = watchdog_timeout(
+            env::var(WATCHDOG_USEC_ENV).ok().as_deref(),
+            env::var(WATCHDOG_PID_ENV).ok().as_deref(),
+            process::id(),
+        )?;
+
+        Some(Self::new(notifier, timeout))
+    }
+
+    /// Creates the watchdog for the given timeout.
+    /// Pings are sent at half the timeout so that a late ping doesn't fail the service.
+    pub fn new(notifier: Notifier, timeout: Duration) -> Self {
+        Self {
+            notifier,
+            interval: timeout / 2,
+            last_ping: None,
+        }
+    }
+
+    /// Returns the time until the next ping is due
+    pub fn until_due(&self) -> Duration {
+        match self.last_ping {
+            Some(last_ping) => self.interval.saturating_sub(last_ping.elapsed()),
+            None => Duration::from_secs(0),
+        }
+    }
+
+    /// Sends a ping if the last one was sent more than an interval ago
+    pub fn ping_if_due(&mut self) {
+        if self.until_due() == Duration::from_secs(0) {
+            self.notifier.send("WATCHDOG=1");
+            self.last_ping = Some(Instant::now());
+        }
+    }
+}
+
+/// Sets the status to the number of living nodes whenever it changes
+pub async fn report_status(notifier: Notifier, context: RunContext) {
+    let mut last_status = String::new();
+
+    loop {
+        let nodes = context.node_states();
+        let alive = nodes
+            .iter()
+            .filter(|(_, state)| matches!(state, NodeState::Alive(_)))
+            .count();
+        let status = format!("{} of {} nodes alive", alive, nodes.len());
+
+        if status != last_status {
+            notifier.status(&status);
+            last_status = status;
+        }
+        task::sleep(STATUS_INTERVAL).await;
+    }
+}
+
+/// Returns the watchdog timeout if the pings are expected from the process with the given pid
+fn watchdog_timeout(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    let usec: u64 = usec?.parse().ok().filter(|usec| *usec > 0)?;
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok()? != own_pid {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec))
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::io;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+
+    /// An unbound datagram socket together with the address of the notify socket
+    pub struct NotifySocket {
+        socket: UnixDatagram,
+        address: NotifyAddress,
+    }
+
+    enum NotifyAddress {
+        Path(PathBuf),
+        #[cfg(target_os = "linux")]
+        Abstract(std::os::unix::net::SocketAddr),
+    }
+
+    impl NotifySocket {
+        pub fn new(address: &str) -> io::Result<Self> {
+            let address = match address.strip_prefix('@') {
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    NotifyAddress::Abstract(std::os::unix::net::SocketAddr::from_abstract_name(
+                        name,
+                    )?)
+                }
+                #[cfg(not(target_os = "linux"))]
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "Abstract sockets are only supported on linux",
+                    ))
+                }
+                None => NotifyAddress::Path(PathBuf::from(address)),
+            };
+
+            Ok(Self {
+                socket: UnixDatagram::unbound()?,
+                address,
+            })
+        }
+
+        pub fn send(&self, message: &[u8]) -> io::Result<()> {
+            match &self.address {
+                NotifyAddress::Path(path) => self.socket.send_to(message, path)?,
+                #[cfg(target_os = "linux")]
+                NotifyAddress::Abstract(address) => self.socket.send_to_addr(message, address)?,
+            };
+
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+
+    fn notify_socket(name: &str) -> (UnixDatagram, PathBuf) {
+        let path = env::temp_dir().join(format!("snekcloud-{}-{}.sock", name, process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        (socket, path)
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buffer = [0u8; 256];
+        let length = socket.recv(&mut buffer).unwrap();
+
+        String::from_utf8_lossy(&buffer[..length]).to_string()
+    }
+
+    #[test]
+    fn it_sends_states_to_the_socket() {
+        let (socket, path) = notify_socket("states");
+        let notifier = Notifier::new(&path.to_string_lossy()).unwrap();
+
+        notifier.ready();
+        notifier.status("1 of 2 nodes alive");
+        notifier.stopping();
+
+        assert_eq!(receive(&socket), "READY=1");
+        assert_eq!(receive(&socket), "STATUS=1 of 2 nodes alive");
+        assert_eq!(receive(&socket), "STOPPING=1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_pings_the_watchdog_once_per_interval() {
+        let (socket, path) = notify_socket("watchdog");
+        let notifier = Notifier::new(&path.to_string_lossy()).unwrap();
+        let mut watchdog = Watchdog::new(notifier, Duration::from_secs(60));
+
+        watchdog.ping_if_due();
+        watchdog.ping_if_due();
+
+        assert_eq!(receive(&socket), "WATCHDOG=1");
+        socket.set_nonblocking(true).unwrap();
+        assert!(socket.recv(&mut [0u8; 16]).is_err());
+        assert!(watchdog.until_due() > Duration::from_secs(29));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_reads_the_watchdog_timeout() {
+        assert_eq!(
+            watchdog_timeout(Some("2000000"), None, 10),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_timeout(Some("2000000"), Some("10"), 10),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(watchdog_timeout(Some("2000000"), Some("11"), 10), None);
+        assert_eq!(watchdog_timeout(Some("0"), None, 10), None);
+        assert_eq!(watchdog_timeout(None, None, 10), None);
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::modules::topics::{NodeRemoved, RemovalReason, NODE_REMOVED};
+use crate::server::acl::Acl;
+use crate::server::bus::EventBus;
+use crate::server::limits::Limiter;
+use crate::utils::settings::{load_settings, Settings};
+use async_std::sync::{Receiver, Sender};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
+use std::sync::Arc;
+use vented::server::data::NodeData;
+
+/// Reloads the configuration every time a reload is requested
+/// and pushes the new settings to the server and the modules
+#[allow(clippy::too_many_arguments)]
+pub async fn handle_reloads(
+    mut current: Settings,
+    requests: Receiver<()>,
+    nodes: Arc<Mutex<HashMap<String, NodeData>>>,
+    trusted_nodes: Arc<RwLock<Vec<String>>>,
+    acl: Acl,
+    limiter: Limiter,
+    bus: EventBus,
+    modules: Vec<(String, Sender<Settings>)>,
+) {
+    while requests.recv().await.is_ok() {
+        let settings = match reload_settings(&current) {
+            Some(settings) => settings,
+            None => continue,
+        };
+        *trusted_nodes.write() = settings.trusted_nodes.clone();
+        let untrusted = apply_trusted_nodes(&nodes, &settings.trusted_nodes);
+        publish_untrusted(&bus, untrusted);
+        acl.update(settings.acl.clone());
+        limiter.update(settings.limits.clone());
+        for (name, module) in &modules {
+            // modules that stopped running or didn't apply the previous settings yet are skipped
+            if module.try_send(settings.clone()).is_err() {
+                log::warn!("Module {} can't be reconfigured right now", name);
+            }
+        }
+        current = settings;
+        log::info!("Configuration reloaded");
+    }
+}
+
+/// Loads and validates the configuration.
+/// Changes to settings that can't be applied at runtime are dropped.
+fn reload_settings(current: &Settings) -> Option<Settings> {
+    let mut settings = match load_settings(&current.config_dir) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Failed to reload the configuration: {}", e);
+            return None;
+        }
+    };
+    if settings.check().is_err() {
+        log::error!("Keeping the current configuration");
+        return None;
+    }
+    for key in settings.reject_immutable_changes(current) {
+        log::error!(
+            "Setting {} can't be changed while the server is running. Restart the server to apply it",
+            key
+        );
+    }
+
+    Some(settings)
+}
+
+/// Updates the trust of all known nodes and returns the nodes that are no longer trusted
+pub fn apply_trusted_nodes(
+    nodes: &Mutex<HashMap<String, NodeData>>,
+    trusted_nodes: &[String],
+) -> Vec<String> {
+    let mut untrusted = Vec::new();
+
+    for node in nodes.lock().values_mut() {
+        let node = node.node_mut();
+        let trusted = trusted_nodes.contains(&node.id);
+
+        if node.trusted != trusted {
+            log::info!("Node {} is now {}", node.id, trust_name(trusted));
+            node.trusted = trusted;
+            if !trusted {
+                untrusted.push(node.id.clone());
+            }
+        }
+    }
+
+    untrusted
+}
+
+/// Announces the nodes that are no longer trusted on the bus
+pub fn publish_untrusted(bus: &EventBus, untrusted: Vec<String>) {
+    for node in untrusted {
+        bus.publish(
+            &NODE_REMOVED,
+            NodeRemoved {
+                node,
+                reason: RemovalReason::Untrusted,
+            },
+        );
+    }
+}
+
+fn trust_name(trusted: bool) -> &'static str {
+    if trusted {
+        "trusted"
+    } else {
+        "untrusted"
+    }
+}
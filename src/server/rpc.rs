@@ -0,0 +1,148 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use futures::channel::oneshot;
+use parking_lot::Mutex;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use vented::event::Event;
+
+/// The event all responses to requests are sent with
+pub const RPC_RESPONSE_EVENT: &str = "rpc:response";
+
+/// Wraps the payload of a request with the id the response is matched by
+#[derive(Serialize, Deserialize)]
+pub struct RequestEnvelope<T> {
+    pub request_id: u64,
+    pub payload: T,
+}
+
+/// Wraps the result of a request with the id of the request
+#[derive(Serialize, Deserialize)]
+pub struct ResponseEnvelope<T> {
+    pub request_id: u64,
+    pub result: Result<T, String>,
+}
+
+/// The requests that are waiting for a response
+#[derive(Clone, Default)]
+pub struct PendingRequests {
+    next_id: Arc<AtomicU64>,
+    requests: Arc<Mutex<HashMap<u64, PendingRequest>>>,
+}
+
+struct PendingRequest {
+    node: String,
+    sender: oneshot::Sender<Event>,
+}
+
+/// Removes the pending request when the requester stops waiting for it,
+/// e.g. because the request future was dropped
+pub struct PendingRequestGuard {
+    id: u64,
+    requests: Arc<Mutex<HashMap<u64, PendingRequest>>>,
+}
+
+impl PendingRequestGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for PendingRequestGuard {
+    fn drop(&mut self) {
+        self.requests.lock().remove(&self.id);
+    }
+}
+
+impl PendingRequests {
+    /// Adds a pending request to the given node and returns the guard that
+    /// holds its id together with the receiver for the response event
+    pub fn add(&self, node: String) -> (PendingRequestGuard, oneshot::Receiver<Event>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.requests
+            .lock()
+            .insert(id, PendingRequest { node, sender });
+        let guard = PendingRequestGuard {
+            id,
+            requests: Arc::clone(&self.requests),
+        };
+
+        (guard, receiver)
+    }
+
+    /// Passes the response event to the request it belongs to.
+    /// Responses from other nodes than the one the request was sent to are ignored.
+    pub fn resolve(&self, event: Event) {
+        let request_id = match event.get_payload::<ResponseEnvelope<IgnoredAny>>() {
+            Ok(envelope) => envelope.request_id,
+            Err(e) => {
+                log::warn!("Received invalid response: {}", e);
+                return;
+            }
+        };
+        let mut requests = self.requests.lock();
+
+        match requests.get(&request_id) {
+            Some(request) if event.origin.as_ref() == Some(&request.node) => {
+                if let Some(request) = requests.remove(&request_id) {
+                    // the requester might have stopped waiting
+                    let _ = request.sender.send(event);
+                }
+            }
+            _ => log::debug!(
+                "Received response to unknown request {} from {:?}",
+                request_id,
+                event.origin
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(request_id: u64, origin: &str) -> Event {
+        let mut event = Event::with_payload(
+            RPC_RESPONSE_EVENT,
+            &ResponseEnvelope {
+                request_id,
+                result: Ok(()),
+            },
+        );
+        event.origin = Some(origin.to_string());
+
+        event
+    }
+
+    #[test]
+    fn it_forgets_requests_that_are_dropped() {
+        let pending = PendingRequests::default();
+        let (guard, receiver) = pending.add("node1".to_string());
+        assert_eq!(pending.requests.lock().len(), 1);
+
+        drop(receiver);
+        drop(guard);
+        assert!(pending.requests.lock().is_empty());
+    }
+
+    #[test]
+    fn it_resolves_responses_from_the_requested_node() {
+        let pending = PendingRequests::default();
+        let (guard, mut receiver) = pending.add("node1".to_string());
+
+        pending.resolve(response(guard.id(), "node2"));
+        assert!(receiver.try_recv().unwrap().is_none());
+        pending.resolve(response(guard.id(), "node1"));
+        assert!(receiver.try_recv().unwrap().is_some());
+        assert!(pending.requests.lock().is_empty());
+    }
+}
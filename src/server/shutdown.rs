@@ -0,0 +1,43 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use async_std::sync::{channel, Receiver, Sender};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// Notifies all clones when the server is shutting down
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<Mutex<Option<Sender<()>>>>,
+    receiver: Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel(1);
+
+        Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+            receiver,
+        }
+    }
+
+    /// Triggers the shutdown
+    pub fn trigger(&self) {
+        // dropping the only sender closes the channel for all receivers
+        self.sender.lock().take();
+    }
+
+    /// Returns if the shutdown has been triggered
+    pub fn is_triggered(&self) -> bool {
+        self.sender.lock().is_none()
+    }
+
+    /// Waits until the shutdown has been triggered
+    pub async fn wait(&self) {
+        while self.receiver.recv().await.is_ok() {}
+    }
+}
@@ -0,0 +1,46 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::server::shutdown::Shutdown;
+use async_std::sync::Sender;
+
+/// Triggers the shutdown when the process receives SIGINT or SIGTERM
+/// and requests a reload of the configuration on SIGHUP.
+/// A second shutdown signal terminates the process immediately.
+#[cfg(unix)]
+pub fn handle_signals(shutdown: Shutdown, reload: Sender<()>) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    std::thread::Builder::new()
+        .name("signal-handler".to_string())
+        .spawn(move || {
+            for signal in signals.forever() {
+                if signal == SIGHUP {
+                    log::info!("Received signal {}. Reloading the configuration...", signal);
+                    // a full channel means that a reload is already pending
+                    let _ = reload.try_send(());
+                    continue;
+                }
+                if shutdown.is_triggered() {
+                    log::warn!("Received signal {} again. Exiting immediately", signal);
+                    std::process::exit(1);
+                }
+                log::info!("Received signal {}. Shutting down...", signal);
+                shutdown.trigger();
+            }
+        })?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_signals(_shutdown: Shutdown, _reload: Sender<()>) -> std::io::Result<()> {
+    log::warn!("Graceful shutdown and reloading on signals are only supported on unix systems");
+
+    Ok(())
+}
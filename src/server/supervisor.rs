@@ -0,0 +1,193 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::modules::Module;
+use crate::server::shutdown::Shutdown;
+use crate::server::tick_context::RunContext;
+use crate::utils::result::SnekcloudResult;
+use crate::utils::settings::Settings;
+use async_std::future;
+use async_std::sync::Receiver;
+use async_std::task;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// The lifecycle state of a supervised module
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleState {
+    Running,
+    Restarting,
+    Failed,
+    Stopped,
+}
+
+/// The state of a module together with the error that stopped it last
+#[derive(Serialize, Clone, Debug)]
+pub struct ModuleStatus {
+    pub state: ModuleState,
+    pub restarts: usize,
+    pub last_error: Option<String>,
+}
+
+/// The states of all supervised modules
+#[derive(Clone, Default)]
+pub struct ModuleStates {
+    states: Arc<Mutex<HashMap<String, ModuleStatus>>>,
+}
+
+impl ModuleStates {
+    /// Returns a copy of the states of all modules
+    pub fn get_all(&self) -> HashMap<String, ModuleStatus> {
+        self.states.lock().clone()
+    }
+
+    fn set_state(&self, name: &str, state: ModuleState) {
+        self.update(name, |status| status.state = state)
+    }
+
+    fn update<F: FnOnce(&mut ModuleStatus)>(&self, name: &str, func: F) {
+        let mut states = self.states.lock();
+        let status = states
+            .entry(name.to_string())
+            .or_insert_with(|| ModuleStatus {
+                state: ModuleState::Running,
+                restarts: 0,
+                last_error: None,
+            });
+        func(status)
+    }
+}
+
+/// The reason the run of a module ended
+enum RunOutcome {
+    Finished(SnekcloudResult<()>),
+    Reconfigured(Box<Settings>),
+    Stopped,
+}
+
+/// Runs the module and restarts it according to its restart settings
+/// until the server shuts down. The shutdown hook of the module is called afterwards.
+/// Settings received over `reconfigure` are passed to the module before it is run again.
+pub async fn supervise(
+    name: String,
+    mut module: Box<dyn Module + Send + Sync>,
+    context: RunContext,
+    states: ModuleStates,
+    shutdown: Shutdown,
+    reconfigure: Receiver<Settings>,
+) {
+    let mut settings = module.restart_settings();
+    let mut restarts: VecDeque<Instant> = VecDeque::new();
+    let mut backoff = settings.initial_backoff();
+
+    loop {
+        states.set_state(&name, ModuleState::Running);
+        let outcome = {
+            let run = module.run(RunContext::clone(&context));
+            let stopped = shutdown.wait();
+            let reconfigured = async {
+                match reconfigure.recv().await {
+                    Ok(new_settings) => new_settings,
+                    Err(_) => future::pending().await,
+                }
+            };
+            pin_mut!(stopped);
+            pin_mut!(reconfigured);
+
+            match select(run, select(stopped, reconfigured)).await {
+                Either::Left((result, _)) => RunOutcome::Finished(result),
+                Either::Right((Either::Left(_), _)) => RunOutcome::Stopped,
+                Either::Right((Either::Right((new_settings, _)), _)) => {
+                    RunOutcome::Reconfigured(Box::new(new_settings))
+                }
+            }
+        };
+        let result = match outcome {
+            RunOutcome::Finished(result) => result,
+            RunOutcome::Reconfigured(new_settings) => {
+                log::info!("Reconfiguring module {}", name);
+                if let Err(e) = module.reconfigure(&new_settings) {
+                    log::error!("Failed to reconfigure module {}: {}", name, e);
+                }
+                settings = module.restart_settings();
+                continue;
+            }
+            RunOutcome::Stopped => break,
+        };
+        let failed = result.is_err();
+        match result {
+            Err(e) => {
+                log::error!("Error when ticking module {}: {}", name, e);
+                states.update(&name, |status| status.last_error = Some(e.to_string()));
+            }
+            Ok(_) => log::debug!("Module {} stopped", name),
+        }
+        if !settings.policy.should_restart(failed) {
+            let state = if failed {
+                ModuleState::Failed
+            } else {
+                ModuleState::Stopped
+            };
+            states.set_state(&name, state);
+            break;
+        }
+
+        while let Some(restart) = restarts.front() {
+            if restart.elapsed() > settings.restart_window() {
+                restarts.pop_front();
+            } else {
+                break;
+            }
+        }
+        if restarts.is_empty() {
+            backoff = settings.initial_backoff();
+        }
+        if restarts.len() >= settings.max_restarts {
+            log::error!(
+                "Module {} was restarted {} times within {} seconds. Giving up",
+                name,
+                restarts.len(),
+                settings.restart_window_secs
+            );
+            states.set_state(&name, ModuleState::Failed);
+            break;
+        }
+        restarts.push_back(Instant::now());
+        states.update(&name, |status| {
+            status.state = ModuleState::Restarting;
+            status.restarts += 1;
+        });
+        log::info!("Restarting module {} in {} ms", name, backoff.as_millis());
+
+        let sleep = task::sleep(backoff);
+        let stopped = shutdown.wait();
+        pin_mut!(sleep);
+        pin_mut!(stopped);
+        if let Either::Right(_) = select(sleep, stopped).await {
+            break;
+        }
+        backoff = (backoff * 2).min(settings.max_backoff());
+    }
+    // modules that don't run anymore aren't reconfigured
+    drop(reconfigure);
+    shutdown.wait().await;
+
+    log::debug!("Stopping module {}", name);
+    if let Err(e) = module.shutdown(RunContext::clone(&context)).await {
+        log::error!("Failed to shut down module {}: {}", name, e);
+    }
+    states.update(&name, |status| {
+        if status.state != ModuleState::Failed {
+            status.state = ModuleState::Stopped
+        }
+    });
+}
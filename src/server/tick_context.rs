@@ -4,60 +4,260 @@
  * See LICENSE for more information
  */
 
-use crate::utils::result::SnekcloudError;
-use async_std::sync::Sender;
+use crate::data::storage::{ModuleStorage, Storage};
+use crate::server::bus::{EventBus, Topic};
+use crate::server::clock::Clock;
+use crate::server::control::ControlMethods;
+use crate::server::events::TypedRequest;
+use crate::server::metrics::Metrics;
+use crate::server::rpc::{PendingRequests, RequestEnvelope, ResponseEnvelope};
+use crate::server::supervisor::{ModuleStates, ModuleStatus};
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use async_std::sync::{Receiver, Sender};
+use futures::channel::oneshot;
+use futures::{stream, Future, StreamExt};
 use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::Arc;
+use std::time::Duration;
 use vented::event::Event;
-use vented::server::data::{Node, NodeData};
-use vented::utils::sync::AsyncValue;
+use vented::server::data::{Node, NodeData, NodeState};
+
+/// The maximum number of events sent at the same time by a multicast
+const MAX_PARALLEL_EMITS: usize = 16;
 
 #[derive(Clone)]
 pub struct RunContext {
     nodes: Arc<Mutex<HashMap<String, NodeData>>>,
     event_sender: Sender<EventInvocation>,
     node_id: String,
+    control_methods: ControlMethods,
+    module_states: ModuleStates,
+    pending_requests: PendingRequests,
+    bus: EventBus,
+    storage: Storage,
+    metrics: Metrics,
+    clock: Clock,
 }
 
 pub struct EventInvocation {
-    pub result: AsyncValue<(), SnekcloudError>,
+    pub result: oneshot::Sender<SnekcloudResult<()>>,
     pub event: Event,
     pub target_node: String,
 }
 
+/// The pending result of an emitted event
+pub struct EmitResult {
+    receiver: oneshot::Receiver<SnekcloudResult<()>>,
+    clock: Clock,
+}
+
+impl EmitResult {
+    /// Waits for the event to be delivered for at most the given duration
+    pub async fn wait_with_timeout(self, timeout: Duration) -> SnekcloudResult<()> {
+        match self.clock.timeout(timeout, self.receiver).await? {
+            Ok(result) => result,
+            Err(_) => Err(SnekcloudError::Cancelled),
+        }
+    }
+}
+
+/// The results of an event that was sent to multiple nodes
+pub struct BroadcastResult {
+    results: HashMap<String, SnekcloudResult<()>>,
+}
+
+impl BroadcastResult {
+    /// Returns the result of the delivery for each node
+    pub fn results(&self) -> &HashMap<String, SnekcloudResult<()>> {
+        &self.results
+    }
+
+    /// Returns the number of nodes the event was delivered to
+    pub fn succeeded(&self) -> usize {
+        self.results
+            .values()
+            .filter(|result| result.is_ok())
+            .count()
+    }
+
+    /// Returns the number of nodes the event couldn't be delivered to
+    pub fn failed(&self) -> usize {
+        self.results
+            .values()
+            .filter(|result| matches!(result, Err(e) if !matches!(e, SnekcloudError::Timeout)))
+            .count()
+    }
+
+    /// Returns the number of nodes that didn't receive the event in time
+    pub fn timed_out(&self) -> usize {
+        self.results
+            .values()
+            .filter(|result| matches!(result, Err(SnekcloudError::Timeout)))
+            .count()
+    }
+
+    /// Logs the summary and every failed delivery
+    pub fn log(&self, event_name: &str) {
+        for (node, result) in &self.results {
+            if let Err(e) = result {
+                log::debug!("Failed to send {} to {}: {}", event_name, node, e);
+            }
+        }
+        log::debug!("Sent {}: {}", event_name, self);
+    }
+}
+
+impl fmt::Display for BroadcastResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} delivered, {} failed, {} timed out",
+            self.succeeded(),
+            self.failed(),
+            self.timed_out()
+        )
+    }
+}
+
 impl RunContext {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         node_id: String,
         sender: Sender<EventInvocation>,
         nodes: Arc<Mutex<HashMap<String, NodeData>>>,
+        control_methods: ControlMethods,
+        module_states: ModuleStates,
+        pending_requests: PendingRequests,
+        bus: EventBus,
+        storage: Storage,
+        metrics: Metrics,
     ) -> Self {
         Self {
             nodes,
             node_id,
             event_sender: sender,
+            control_methods,
+            module_states,
+            pending_requests,
+            bus,
+            storage,
+            metrics,
+            clock: Clock::default(),
         }
     }
 
-    pub async fn emit<S: ToString>(
-        &mut self,
-        target_node: S,
-        event: Event,
-    ) -> AsyncValue<(), SnekcloudError> {
-        let value = AsyncValue::new();
+    /// Replaces the clock the modules measure time and wait with
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+
+        self
+    }
+
+    pub async fn emit<S: ToString>(&mut self, target_node: S, event: Event) -> EmitResult {
+        let (sender, receiver) = oneshot::channel();
         self.event_sender
             .send(EventInvocation {
                 event,
                 target_node: target_node.to_string(),
-                result: value.clone(),
+                result: sender,
+            })
+            .await;
+
+        EmitResult {
+            receiver,
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Sends the event to all living nodes
+    pub async fn broadcast(&self, event: Event, timeout: Duration) -> BroadcastResult {
+        self.multicast(|_| true, event, timeout).await
+    }
+
+    /// Sends the event to all living nodes that match the filter.
+    /// At most `MAX_PARALLEL_EMITS` events are sent at the same time.
+    pub async fn multicast<F>(
+        &self,
+        node_filter: F,
+        event: Event,
+        timeout: Duration,
+    ) -> BroadcastResult
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let nodes: Vec<Node> = self
+            .living_nodes()
+            .into_iter()
+            .filter(|node| node_filter(node))
+            .collect();
+        let results = stream::iter(nodes)
+            .map(|node| {
+                let mut context = self.clone();
+                let event = event.clone();
+                async move {
+                    let result = context
+                        .emit(node.id.clone(), event)
+                        .await
+                        .wait_with_timeout(timeout)
+                        .await;
+                    (node.id, result)
+                }
             })
+            .buffer_unordered(MAX_PARALLEL_EMITS)
+            .collect()
             .await;
 
-        value
+        BroadcastResult { results }
+    }
+
+    /// Sends a request to the node and waits for the response for at most the given duration.
+    /// The request needs to be handled with `EventRegistry::on_request` on the receiving node.
+    pub async fn request<Req, Resp>(
+        &mut self,
+        node: &str,
+        request: &TypedRequest<Req, Resp>,
+        payload: &Req,
+        timeout: Duration,
+    ) -> SnekcloudResult<Resp>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let start = self.clock.now();
+        // the guard forgets the request when this future is dropped before the response arrived
+        let (pending_request, receiver) = self.pending_requests.add(node.to_string());
+        let event = Event::with_payload(
+            request.name(),
+            &RequestEnvelope {
+                request_id: pending_request.id(),
+                payload,
+            },
+        );
+        let result = async {
+            self.emit(node, event)
+                .await
+                .wait_with_timeout(timeout)
+                .await?;
+
+            let remaining = timeout.saturating_sub(self.clock.elapsed(start));
+            match self.clock.timeout(remaining, receiver).await? {
+                Ok(event) => Ok(event),
+                Err(_) => Err(SnekcloudError::Cancelled),
+            }
+        }
+        .await;
+        let response: ResponseEnvelope<Resp> = result?.get_payload()?;
+
+        response.result.map_err(SnekcloudError::RemoteError)
     }
 
     /// Returns a copy of the nodes of the server
-    #[allow(dead_code)]
     pub fn nodes(&self) -> Vec<Node> {
         self.nodes
             .lock()
@@ -67,17 +267,33 @@ impl RunContext {
             .collect()
     }
 
+    /// Returns a copy of the nodes together with their current state
+    pub fn node_states(&self) -> Vec<(Node, NodeState)> {
+        self.nodes
+            .lock()
+            .values_mut()
+            .map(|node| {
+                let state = node.node_state().clone();
+                (node.node().clone(), state)
+            })
+            .collect()
+    }
+
     pub fn living_nodes(&self) -> Vec<Node> {
         self.nodes
             .lock()
             .values()
-            .cloned()
             .filter(|node| !node.is_dead())
+            .cloned()
             .map(Node::from)
             .collect()
     }
 
-    #[allow(dead_code)]
+    /// Returns if the node is known to the server
+    pub fn has_node(&self, node_id: &str) -> bool {
+        self.nodes.lock().contains_key(node_id)
+    }
+
     pub fn check_alive(&self, node_id: &String) -> bool {
         if let Some(node) = self.nodes.lock().get(node_id) {
             !node.is_dead()
@@ -90,4 +306,49 @@ impl RunContext {
     pub fn node_id(&self) -> &String {
         &self.node_id
     }
+
+    /// Returns the states of all modules run by the server
+    pub fn module_states(&self) -> HashMap<String, ModuleStatus> {
+        self.module_states.get_all()
+    }
+
+    /// Publishes the message on the local event bus
+    pub fn publish<T: Clone + Send + 'static>(&self, topic: &Topic<T>, message: T) {
+        self.bus.publish(topic, message)
+    }
+
+    /// Subscribes to the topic on the local event bus
+    pub fn subscribe<T: Send + 'static>(&self, topic: &Topic<T>) -> Receiver<T> {
+        self.bus.subscribe(topic)
+    }
+
+    /// Returns the persistent storage for the given namespace
+    pub fn storage(&self, namespace: &str) -> SnekcloudResult<ModuleStorage> {
+        self.storage.namespace(namespace)
+    }
+
+    /// Returns the metrics of the server
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the clock the modules measure time and wait with
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Calls a method registered by the server or a module
+    #[cfg(test)]
+    pub async fn call_control_method(&self, name: &str, params: Value) -> SnekcloudResult<Value> {
+        self.control_methods.call(name, params).await
+    }
+
+    /// Registers a method that can be called via the control socket
+    pub fn register_control_method<F, Fut>(&self, name: &str, method: F)
+    where
+        F: Fn(Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = SnekcloudResult<Value>> + Send + 'static,
+    {
+        self.control_methods.register(name, method)
+    }
 }
@@ -0,0 +1,372 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::modules::registry::ModuleRegistry;
+use crate::modules::Module;
+use crate::server::events::EventRegistry;
+use crate::server::shutdown::Shutdown;
+use crate::server::tick_context::RunContext;
+use crate::server::SnekcloudServer;
+use crate::utils::keys::generate_private_key;
+use crate::utils::result::SnekcloudResult;
+use crate::utils::settings::{RestartSettings, Settings};
+use async_std::sync::{channel, Receiver};
+use async_trait::async_trait;
+use parking_lot::Mutex;
+use std::env;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use vented::server::data::{Node, NodeState};
+use vented::stream::SecretKey;
+
+pub mod simulation;
+
+/// How long to wait for the nodes to listen and run their modules
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often conditions are checked while waiting for them
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Gives every network of the process its own directory
+static NETWORK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Runs several servers with their own settings and keys in one process.
+/// The nodes listen on loopback ports and know each other unless configured otherwise.
+/// All nodes are stopped and their files are removed when the network is dropped.
+pub struct TestNetwork {
+    dir: PathBuf,
+    nodes: Vec<TestNode>,
+}
+
+struct TestNode {
+    settings: Settings,
+    private_key: SecretKey,
+    unknown_nodes: Vec<usize>,
+    context: Arc<Mutex<Option<RunContext>>>,
+    running: Option<RunningNode>,
+    /// Keeps the port of the node bound until the first nodes of the network start
+    port_reservation: Option<TcpListener>,
+}
+
+struct RunningNode {
+    shutdown: Shutdown,
+    handle: JoinHandle<SnekcloudResult<()>>,
+}
+
+impl TestNetwork {
+    /// Creates a network of nodes with the ids `node0` to `node{count - 1}`.
+    /// The settings can be changed until the nodes are started.
+    pub fn new(count: usize) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "snekcloud-test-{}-{}",
+            process::id(),
+            NETWORK_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let nodes = (0..count).map(|index| TestNode::new(&dir, index)).collect();
+
+        Self { dir, nodes }
+    }
+
+    /// Returns the id of the node
+    pub fn node_id(&self, index: usize) -> String {
+        self.nodes[index].settings.node_id.clone()
+    }
+
+    /// Returns the settings of the node
+    pub fn settings(&self, index: usize) -> &Settings {
+        &self.nodes[index].settings
+    }
+
+    /// Returns the settings the node is started with
+    pub fn settings_mut(&mut self, index: usize) -> &mut Settings {
+        &mut self.nodes[index].settings
+    }
+
+    /// Removes the other node from the nodes the node knows on startup
+    pub fn forget(&mut self, index: usize, other: usize) {
+        self.nodes[index].unknown_nodes.push(other);
+    }
+
+    /// Makes the node trust the other node
+    pub fn trust(&mut self, index: usize, other: usize) {
+        let other_id = self.node_id(other);
+        self.nodes[index].settings.trusted_nodes.push(other_id);
+    }
+
+    /// Starts all nodes that aren't running yet
+    pub fn start(&mut self) {
+        let indices: Vec<usize> = (0..self.nodes.len())
+            .filter(|index| self.nodes[*index].running.is_none())
+            .collect();
+        self.start_nodes(&indices);
+    }
+
+    /// Starts the nodes and waits until all of them are listening.
+    /// The modules only start running afterwards so that the first events
+    /// don't fail because another node isn't listening yet.
+    pub fn start_nodes(&mut self, indices: &[usize]) {
+        let (ready_tx, ready_rx) = channel(1);
+        // the servers bind their addresses themselves without reporting the port
+        // so the ports are only released right before they start.
+        // Nodes that aren't started become unreachable.
+        for node in &mut self.nodes {
+            node.port_reservation.take();
+        }
+        for index in indices {
+            self.spawn_node(*index, ready_rx.clone());
+        }
+        for index in indices {
+            let node = &self.nodes[*index];
+            for address in &node.settings.listen_addresses {
+                let listening = wait_until(START_TIMEOUT, || TcpStream::connect(address).is_ok());
+                assert!(listening, "Node {} didn't listen on {}", index, address);
+            }
+            let running = wait_until(START_TIMEOUT, || node.context.lock().is_some());
+            assert!(running, "Node {} didn't run its modules", index);
+        }
+        // closing the channel releases the modules
+        drop(ready_tx);
+    }
+
+    fn spawn_node(&mut self, index: usize, ready: Receiver<()>) {
+        let keys = self.known_nodes(index);
+        let node = &mut self.nodes[index];
+        assert!(node.running.is_none(), "Node {} is already running", index);
+        fs::create_dir_all(&node.settings.node_data_dir).expect("Failed to create node dir");
+
+        let mut server =
+            SnekcloudServer::new(node.settings.clone(), node.private_key.clone(), keys)
+                .expect("Failed to create server");
+        for address in &node.settings.listen_addresses {
+            server.add_listen_address(address.clone());
+        }
+        for module in ModuleRegistry::default().build(&node.settings) {
+            server
+                .register_module(
+                    DelayedModule {
+                        module,
+                        ready: ready.clone(),
+                    }
+                    .boxed(),
+                )
+                .expect("Failed to register module");
+        }
+        server
+            .register_module(
+                ContextProbe {
+                    context: Arc::clone(&node.context),
+                }
+                .boxed(),
+            )
+            .expect("Failed to register module");
+
+        node.running = Some(RunningNode {
+            shutdown: server.shutdown(),
+            handle: thread::spawn(move || server.run()),
+        });
+    }
+
+    /// Returns the nodes the node knows on startup
+    fn known_nodes(&self, index: usize) -> Vec<Node> {
+        let node = &self.nodes[index];
+
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(other, _)| *other != index && !node.unknown_nodes.contains(other))
+            .map(|(_, other)| Node {
+                id: other.settings.node_id.clone(),
+                public_key: other.private_key.public_key(),
+                addresses: other.settings.listen_addresses.clone(),
+                trusted: node
+                    .settings
+                    .trusted_nodes
+                    .contains(&other.settings.node_id),
+            })
+            .collect()
+    }
+
+    /// Returns the context the modules of the running node are run with
+    pub fn context(&self, index: usize) -> RunContext {
+        self.nodes[index]
+            .context
+            .lock()
+            .clone()
+            .unwrap_or_else(|| panic!("Node {} isn't running", index))
+    }
+
+    /// Returns if the node considers the other node alive
+    pub fn sees_alive(&self, index: usize, other: usize) -> bool {
+        let other_id = self.node_id(other);
+
+        self.context(index)
+            .node_states()
+            .iter()
+            .any(|(node, state)| node.id == other_id && matches!(state, NodeState::Alive(_)))
+    }
+
+    /// Returns if all running nodes consider the running nodes they know alive
+    pub fn all_alive(&self) -> bool {
+        let running: Vec<usize> = (0..self.nodes.len())
+            .filter(|index| self.nodes[*index].running.is_some())
+            .collect();
+
+        running.iter().all(|index| {
+            running
+                .iter()
+                .filter(|other| *other != index)
+                .filter(|other| !self.nodes[*index].unknown_nodes.contains(other))
+                .all(|other| self.sees_alive(*index, *other))
+        })
+    }
+
+    /// Waits until the condition is met and returns false if it wasn't met in time
+    pub fn wait_for<F: Fn(&Self) -> bool>(&self, timeout: Duration, condition: F) -> bool {
+        wait_until(timeout, || condition(self))
+    }
+
+    /// Shuts all running nodes down.
+    /// The listeners of the nodes can't be closed and accept connections until the process exits
+    /// but the stopped nodes don't handle the events they receive.
+    pub fn stop(&mut self) {
+        let running: Vec<RunningNode> = self
+            .nodes
+            .iter_mut()
+            .filter_map(|node| node.running.take())
+            .collect();
+
+        for node in &running {
+            node.shutdown.trigger();
+        }
+        for node in running {
+            match node.handle.join() {
+                Ok(Err(e)) => log::error!("Node stopped with an error: {}", e),
+                Err(_) => log::error!("Node panicked"),
+                Ok(Ok(_)) => {}
+            }
+        }
+    }
+}
+
+impl Drop for TestNetwork {
+    fn drop(&mut self) {
+        self.stop();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl TestNode {
+    fn new(dir: &Path, index: usize) -> Self {
+        let node_dir = dir.join(format!("node{}", index));
+        let port_reservation =
+            TcpListener::bind("127.0.0.1:0").expect("Failed to find a free port");
+        let address = port_reservation
+            .local_addr()
+            .expect("Failed to get the address of the reserved port");
+        let settings = Settings {
+            node_id: format!("node{}", index),
+            listen_addresses: vec![address.to_string()],
+            private_key: node_dir.join("private_key"),
+            node_data_dir: node_dir.join("nodes"),
+            log_folder: node_dir.join("logs"),
+            database_path: node_dir.join("snekcloud.db"),
+            control_socket: None,
+            shutdown_timeout_secs: 1,
+            config_dir: node_dir,
+            ..Settings::default()
+        };
+
+        Self {
+            settings,
+            private_key: generate_private_key(),
+            unknown_nodes: Vec::new(),
+            context: Arc::new(Mutex::new(None)),
+            running: None,
+            port_reservation: Some(port_reservation),
+        }
+    }
+}
+
+/// Holds the module back until the receiver is closed
+struct DelayedModule {
+    module: Box<dyn Module + Send + Sync>,
+    ready: Receiver<()>,
+}
+
+#[async_trait]
+impl Module for DelayedModule {
+    fn name(&self) -> String {
+        self.module.name()
+    }
+
+    fn init(&mut self, events: &mut EventRegistry) -> SnekcloudResult<()> {
+        self.module.init(events)
+    }
+
+    fn boxed(self) -> Box<dyn Module + Send + Sync> {
+        Box::new(self)
+    }
+
+    async fn run(&mut self, context: RunContext) -> SnekcloudResult<()> {
+        while self.ready.recv().await.is_ok() {}
+        self.module.run(context).await
+    }
+
+    fn restart_settings(&self) -> RestartSettings {
+        self.module.restart_settings()
+    }
+
+    fn reconfigure(&mut self, settings: &Settings) -> SnekcloudResult<()> {
+        self.module.reconfigure(settings)
+    }
+
+    async fn shutdown(&mut self, context: RunContext) -> SnekcloudResult<()> {
+        self.module.shutdown(context).await
+    }
+}
+
+/// Hands the context of the running node to the network
+struct ContextProbe {
+    context: Arc<Mutex<Option<RunContext>>>,
+}
+
+#[async_trait]
+impl Module for ContextProbe {
+    fn name(&self) -> String {
+        "test_context_probe".to_string()
+    }
+
+    fn init(&mut self, _events: &mut EventRegistry) -> SnekcloudResult<()> {
+        Ok(())
+    }
+
+    fn boxed(self) -> Box<dyn Module + Send + Sync> {
+        Box::new(self)
+    }
+
+    async fn run(&mut self, context: RunContext) -> SnekcloudResult<()> {
+        self.context.lock().replace(context);
+        futures::future::pending().await
+    }
+}
+
+fn wait_until<F: Fn() -> bool>(timeout: Duration, condition: F) -> bool {
+    let start = Instant::now();
+
+    while !condition() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    true
+}
@@ -0,0 +1,688 @@
+/*
+ * snekcloud node based network
+ * Copyright (C) 2020 trivernis
+ * See LICENSE for more information
+ */
+
+use crate::data::storage::Storage;
+use crate::modules::topics::{NodeRemoved, RemovalReason, NODE_REMOVED};
+use crate::modules::Module;
+use crate::server::acl::Acl;
+use crate::server::bus::EventBus;
+use crate::server::clock::{Clock, TimeSource};
+use crate::server::control::ControlMethods;
+use crate::server::events::{EventHandler, EventRegistry, EventTarget};
+use crate::server::limits::Limiter;
+use crate::server::metrics::Metrics;
+use crate::server::rpc::{PendingRequests, RPC_RESPONSE_EVENT};
+use crate::server::supervisor::ModuleStates;
+use crate::server::tick_context::{EventInvocation, RunContext};
+use crate::utils::keys::generate_private_key;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
+use crate::utils::settings::{AclSettings, LimitSettings};
+use async_std::sync::{channel, Receiver};
+use futures::channel::oneshot;
+use futures::executor::{LocalPool, LocalSpawner};
+use futures::future::{abortable, AbortHandle, BoxFuture};
+use futures::task::{Context, LocalSpawnExt, Poll, Waker};
+use futures::{Future, FutureExt};
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vented::event::Event;
+use vented::server::data::{Node, NodeData, NodeState};
+use vented::utils::result::VentedError;
+
+/// How long it takes until an event that was lost is reported as undeliverable
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A clock that only advances when the simulation advances it
+#[derive(Clone)]
+pub struct VirtualClock {
+    state: Arc<Mutex<ClockState>>,
+}
+
+struct ClockState {
+    start: Instant,
+    elapsed: Duration,
+    next_timer: u64,
+    timers: BTreeMap<(Duration, u64), Waker>,
+}
+
+impl VirtualClock {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClockState {
+                start: Instant::now(),
+                elapsed: Duration::from_secs(0),
+                next_timer: 0,
+                timers: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Returns the simulated time since the clock was created
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().elapsed
+    }
+
+    /// Returns when the next timer expires
+    fn next_deadline(&self) -> Option<Duration> {
+        self.state
+            .lock()
+            .timers
+            .keys()
+            .next()
+            .map(|(deadline, _)| *deadline)
+    }
+
+    /// Advances the time and wakes all timers that expired
+    fn advance_to(&self, time: Duration) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock();
+            state.elapsed = state.elapsed.max(time);
+            let now = state.elapsed;
+            let pending = state.timers.split_off(&(now, u64::MAX));
+
+            std::mem::replace(&mut state.timers, pending)
+                .into_values()
+                .collect()
+        };
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl TimeSource for VirtualClock {
+    fn now(&self) -> Instant {
+        let state = self.state.lock();
+
+        state.start + state.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(Sleep {
+            deadline: self.elapsed() + duration,
+            timer: None,
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+/// Waits until the virtual clock reaches the deadline
+struct Sleep {
+    state: Arc<Mutex<ClockState>>,
+    deadline: Duration,
+    timer: Option<u64>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let state = Arc::clone(&self.state);
+        let mut state = state.lock();
+        if state.elapsed >= self.deadline {
+            return Poll::Ready(());
+        }
+        let timer = match self.timer {
+            Some(timer) => timer,
+            None => {
+                state.next_timer += 1;
+                self.timer = Some(state.next_timer);
+                state.next_timer
+            }
+        };
+        state
+            .timers
+            .insert((self.deadline, timer), cx.waker().clone());
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(timer) = self.timer {
+            self.state.lock().timers.remove(&(self.deadline, timer));
+        }
+    }
+}
+
+/// The conditions of the link from one node to another
+#[derive(Clone, Copy, Debug)]
+pub struct LinkConditions {
+    /// The time it takes to deliver an event
+    pub latency: Duration,
+    /// The maximum random delay added to the latency
+    pub jitter: Duration,
+    /// The probability that an event gets lost
+    pub loss: f64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(1),
+            jitter: Duration::from_secs(0),
+            loss: 0.0,
+        }
+    }
+}
+
+/// What happens to an event sent over a link
+enum Route {
+    Deliver(Duration),
+    Refuse(Duration),
+    Lose,
+}
+
+/// The handlers registered by the modules of a simulated node
+#[derive(Default)]
+struct HandlerMap {
+    handlers: HashMap<String, Vec<Arc<EventHandler>>>,
+}
+
+impl EventTarget for HandlerMap {
+    fn on(&mut self, event_name: &str, handler: EventHandler) {
+        self.handlers
+            .entry(event_name.to_string())
+            .or_default()
+            .push(Arc::new(handler));
+    }
+}
+
+/// The state of the simulated network
+struct Network {
+    seed: u64,
+    node_ids: Vec<String>,
+    default_conditions: LinkConditions,
+    conditions: HashMap<(usize, usize), LinkConditions>,
+    partitions: HashSet<(usize, usize)>,
+    crashed: HashSet<usize>,
+    links: HashMap<(usize, usize), StdRng>,
+    sent: HashMap<(usize, usize), usize>,
+}
+
+impl Network {
+    fn index_of(&self, node_id: &str) -> Option<usize> {
+        self.node_ids.iter().position(|id| id == node_id)
+    }
+
+    /// Decides what happens to the next event on the link.
+    /// Every link draws from its own random generator so that the outcome
+    /// only depends on the seed and the order of events on that link.
+    fn route(&mut self, from: usize, to: usize) -> Route {
+        *self.sent.entry((from, to)).or_default() += 1;
+        let conditions = self
+            .conditions
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(self.default_conditions);
+        let seed = self.seed;
+        let rng = self
+            .links
+            .entry((from, to))
+            .or_insert_with(|| StdRng::seed_from_u64(link_seed(seed, from, to)));
+
+        let jitter = conditions.jitter.as_nanos() as u64;
+        let latency = if jitter > 0 {
+            conditions.latency + Duration::from_nanos(rng.gen_range(0, jitter + 1))
+        } else {
+            conditions.latency
+        };
+        let lost = rng.gen_bool(conditions.loss.clamp(0.0, 1.0));
+
+        if self.crashed.contains(&to) {
+            Route::Refuse(latency)
+        } else if lost || self.partitions.contains(&(from, to)) {
+            Route::Lose
+        } else {
+            Route::Deliver(latency)
+        }
+    }
+}
+
+/// Everything the tasks of the simulation share
+#[derive(Clone)]
+struct Shared {
+    network: Arc<Mutex<Network>>,
+    clock: VirtualClock,
+    spawner: LocalSpawner,
+    handlers: Vec<Arc<Mutex<HandlerMap>>>,
+    nodes: Vec<Arc<Mutex<HashMap<String, NodeData>>>>,
+}
+
+impl Shared {
+    /// Emits the events of the node over the simulated network
+    async fn transport(self, from: usize, invocations: Receiver<EventInvocation>) {
+        while let Ok(invocation) = invocations.recv().await {
+            let shared = self.clone();
+            self.spawn(async move { shared.deliver(from, invocation).await });
+        }
+    }
+
+    /// Sends an emitted event and reports the result to the emitter
+    async fn deliver(self, from: usize, invocation: EventInvocation) {
+        let route = {
+            let mut network = self.network.lock();
+            network
+                .index_of(&invocation.target_node)
+                .map(|to| (to, network.route(from, to)))
+        };
+        let (to, route) = match route {
+            Some(route) => route,
+            None => {
+                let _ = invocation
+                    .result
+                    .send(Err(SnekcloudError::UnknownNode(invocation.target_node)));
+                return;
+            }
+        };
+        let delivered = match route {
+            Route::Deliver(latency) => {
+                self.clock.sleep(latency).await;
+                // the node might have crashed while the event was on its way
+                !self.is_crashed(to) && {
+                    self.dispatch(from, to, invocation.event);
+                    true
+                }
+            }
+            Route::Refuse(latency) => {
+                self.clock.sleep(latency).await;
+                false
+            }
+            Route::Lose => {
+                self.clock.sleep(SEND_TIMEOUT).await;
+                false
+            }
+        };
+        let now = self.clock.now();
+        let state = if delivered {
+            NodeState::Alive(now)
+        } else {
+            NodeState::Dead(now)
+        };
+        if let Some(node) = self.nodes[from].lock().get_mut(&invocation.target_node) {
+            node.set_node_state(state);
+        }
+        let result = if delivered {
+            Ok(())
+        } else {
+            Err(SnekcloudError::from(VentedError::UnreachableNode(
+                invocation.target_node,
+            )))
+        };
+        let _ = invocation.result.send(result);
+    }
+
+    /// Passes the event to the handlers of the receiving node
+    /// and sends their responses back to the sender
+    fn dispatch(&self, from: usize, to: usize, mut event: Event) {
+        event.origin = Some(self.network.lock().node_ids[from].clone());
+        let handlers = self.handlers[to]
+            .lock()
+            .handlers
+            .get(&event.name)
+            .cloned()
+            .unwrap_or_default();
+
+        for handler in handlers {
+            let shared = self.clone();
+            let response = handler(event.clone());
+            self.spawn(async move {
+                if let Some(response) = response.await {
+                    shared.respond(to, from, response).await;
+                }
+            });
+        }
+    }
+
+    /// Sends the response of a handler over the connection the event was received on
+    async fn respond(self, from: usize, to: usize, response: Event) {
+        let route = self.network.lock().route(from, to);
+        if let Route::Deliver(latency) = route {
+            self.clock.sleep(latency).await;
+            if !self.is_crashed(to) && !self.is_crashed(from) {
+                self.dispatch(from, to, response);
+            }
+        }
+    }
+
+    fn is_crashed(&self, index: usize) -> bool {
+        self.network.lock().crashed.contains(&index)
+    }
+
+    fn spawn<F: Future<Output = ()> + 'static>(&self, future: F) {
+        self.spawner
+            .spawn_local(future)
+            .expect("Failed to spawn simulated task");
+    }
+}
+
+/// Creates a new instance of a module whenever the node starts
+type ModuleConstructor = Box<dyn Fn() -> Box<dyn Module + Send + Sync>>;
+
+struct SimulatedNode {
+    context: RunContext,
+    bus: EventBus,
+    metrics: Metrics,
+    acl: Acl,
+    limiter: Limiter,
+    modules: Vec<ModuleConstructor>,
+    running: Vec<AbortHandle>,
+}
+
+impl SimulatedNode {
+    /// Returns the registry the handlers of the node are registered with
+    fn registry<'a>(&self, handlers: &'a mut HandlerMap) -> EventRegistry<'a> {
+        EventRegistry::new(
+            handlers,
+            self.bus.clone(),
+            self.metrics.clone(),
+            self.acl.clone(),
+            self.limiter.clone(),
+        )
+    }
+}
+
+/// Runs the modules of several nodes on a simulated network with a virtual clock.
+/// All tasks run on a single thread and the clock only advances when every task is waiting,
+/// so hours of simulated time pass in seconds and a seed always leads to the same outcome.
+/// Events are emitted through the `RunContext` of each node like on a real server.
+pub struct Simulation {
+    pool: LocalPool,
+    shared: Shared,
+    nodes: Vec<SimulatedNode>,
+}
+
+impl Simulation {
+    /// Creates the nodes `node0` to `node{count - 1}` that all know each other
+    pub fn new(seed: u64, count: usize) -> Self {
+        let pool = LocalPool::new();
+        let node_ids: Vec<String> = (0..count).map(|index| format!("node{}", index)).collect();
+        let known_nodes: Vec<Node> = node_ids
+            .iter()
+            .map(|id| Node {
+                id: id.clone(),
+                public_key: generate_private_key().public_key(),
+                addresses: Vec::new(),
+                trusted: false,
+            })
+            .collect();
+        let nodes: Vec<Arc<Mutex<HashMap<String, NodeData>>>> = node_ids
+            .iter()
+            .map(|own_id| {
+                let nodes = known_nodes
+                    .iter()
+                    .filter(|node| &node.id != own_id)
+                    .map(|node| (node.id.clone(), NodeData::from(node.clone())))
+                    .collect();
+                Arc::new(Mutex::new(nodes))
+            })
+            .collect();
+        let shared = Shared {
+            network: Arc::new(Mutex::new(Network {
+                seed,
+                node_ids: node_ids.clone(),
+                default_conditions: LinkConditions::default(),
+                conditions: HashMap::new(),
+                partitions: HashSet::new(),
+                crashed: HashSet::new(),
+                links: HashMap::new(),
+                sent: HashMap::new(),
+            })),
+            clock: VirtualClock::new(),
+            spawner: pool.spawner(),
+            handlers: (0..count).map(|_| Default::default()).collect(),
+            nodes,
+        };
+        let nodes = (0..count)
+            .map(|index| Self::create_node(&shared, index, node_ids[index].clone()))
+            .collect();
+
+        Self {
+            pool,
+            shared,
+            nodes,
+        }
+    }
+
+    fn create_node(shared: &Shared, index: usize, node_id: String) -> SimulatedNode {
+        let (sender, receiver) = channel(10);
+        let pending_requests = PendingRequests::default();
+        let bus = EventBus::default();
+        let metrics = Metrics::default();
+        let nodes = Arc::clone(&shared.nodes[index]);
+        let acl = Acl::new(AclSettings::default(), Arc::clone(&nodes));
+        // the rate limits are measured in real time which passes much slower
+        let limiter = Limiter::new(LimitSettings {
+            events_per_second: f64::MAX,
+            burst: u32::MAX,
+            ..LimitSettings::default()
+        });
+        let storage =
+            Storage::open(Path::new(":memory:")).expect("Failed to open in memory storage");
+        let context = RunContext::new(
+            node_id,
+            sender,
+            nodes,
+            ControlMethods::default(),
+            ModuleStates::default(),
+            pending_requests.clone(),
+            bus.clone(),
+            storage,
+            metrics.clone(),
+        )
+        .with_clock(Clock::new(shared.clock.clone()));
+        shared.spawn(shared.clone().transport(index, receiver));
+
+        let node = SimulatedNode {
+            context,
+            bus,
+            metrics,
+            acl,
+            limiter,
+            modules: Vec::new(),
+            running: Vec::new(),
+        };
+        node.registry(&mut shared.handlers[index].lock())
+            .on(RPC_RESPONSE_EVENT, move |event| {
+                pending_requests.resolve(event);
+                Box::pin(async { None })
+            });
+
+        node
+    }
+
+    /// Returns the id of the node
+    pub fn node_id(&self, index: usize) -> String {
+        self.shared.network.lock().node_ids[index].clone()
+    }
+
+    /// Adds a module to the node and starts it unless the node crashed.
+    /// The constructor is called again whenever the node restarts.
+    pub fn add_module<F>(&mut self, index: usize, constructor: F)
+    where
+        F: Fn() -> Box<dyn Module + Send + Sync> + 'static,
+    {
+        let module = constructor();
+        self.nodes[index].modules.push(Box::new(constructor));
+
+        if !self.is_crashed(index) {
+            self.start_module(index, module);
+        }
+    }
+
+    /// Initializes the module and runs it until the node crashes
+    fn start_module(&mut self, index: usize, mut module: Box<dyn Module + Send + Sync>) {
+        let node = &mut self.nodes[index];
+        module
+            .init(&mut node.registry(&mut self.shared.handlers[index].lock()))
+            .expect("Failed to initialize module");
+        let context = node.context.clone();
+        let (task, handle) = abortable(async move {
+            if let Err(e) = module.run(context).await {
+                log::error!("Simulated module {} failed: {}", module.name(), e);
+            }
+        });
+        self.shared.spawn(task.map(|_| ()));
+        node.running.push(handle);
+    }
+
+    /// Returns the context the modules of the node are run with
+    pub fn context(&self, index: usize) -> RunContext {
+        self.nodes[index].context.clone()
+    }
+
+    /// Returns the simulated time that passed since the simulation was created
+    pub fn elapsed(&self) -> Duration {
+        self.shared.clock.elapsed()
+    }
+
+    /// Returns how many events the node sent to the other node including responses
+    pub fn sent(&self, from: usize, to: usize) -> usize {
+        self.shared
+            .network
+            .lock()
+            .sent
+            .get(&(from, to))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Sets the conditions of all links that weren't configured individually
+    pub fn set_default_conditions(&mut self, conditions: LinkConditions) {
+        self.shared.network.lock().default_conditions = conditions;
+    }
+
+    /// Sets the conditions of the link from one node to the other
+    pub fn set_conditions(&mut self, from: usize, to: usize, conditions: LinkConditions) {
+        self.shared
+            .network
+            .lock()
+            .conditions
+            .insert((from, to), conditions);
+    }
+
+    /// Drops all events between the two groups of nodes
+    pub fn partition(&mut self, group: &[usize], other_group: &[usize]) {
+        let mut network = self.shared.network.lock();
+        for a in group {
+            for b in other_group {
+                network.partitions.insert((*a, *b));
+                network.partitions.insert((*b, *a));
+            }
+        }
+    }
+
+    /// Removes all partitions
+    pub fn heal(&mut self) {
+        self.shared.network.lock().partitions.clear();
+    }
+
+    /// Makes the node forget the other node and announces it like the node watcher does
+    pub fn remove_node(&mut self, index: usize, other: usize) {
+        let other_id = self.node_id(other);
+        self.shared.nodes[index].lock().remove(&other_id);
+        self.nodes[index].context.publish(
+            &NODE_REMOVED,
+            NodeRemoved {
+                node: other_id,
+                reason: RemovalReason::Deleted,
+            },
+        );
+    }
+
+    /// Stops the modules of the node and refuses all events sent to it
+    pub fn crash(&mut self, index: usize) {
+        self.shared.network.lock().crashed.insert(index);
+        for handle in self.nodes[index].running.drain(..) {
+            handle.abort();
+        }
+    }
+
+    /// Brings a crashed node back with new instances of its modules.
+    /// The context of the node including its storage is kept.
+    pub fn restart(&mut self, index: usize) {
+        self.shared.network.lock().crashed.remove(&index);
+        {
+            let mut handlers = self.shared.handlers[index].lock();
+            handlers
+                .handlers
+                .retain(|event_name, _| event_name == RPC_RESPONSE_EVENT);
+        }
+        let modules: Vec<Box<dyn Module + Send + Sync>> = self.nodes[index]
+            .modules
+            .iter()
+            .map(|constructor| constructor())
+            .collect();
+        for module in modules {
+            self.start_module(index, module);
+        }
+    }
+
+    fn is_crashed(&self, index: usize) -> bool {
+        self.shared.is_crashed(index)
+    }
+
+    /// Runs all tasks until the given amount of simulated time has passed
+    pub fn run_for(&mut self, duration: Duration) {
+        let end = self.elapsed() + duration;
+
+        loop {
+            self.pool.run_until_stalled();
+            match self.shared.clock.next_deadline() {
+                Some(deadline) if deadline <= end => self.shared.clock.advance_to(deadline),
+                _ => break,
+            }
+        }
+        self.shared.clock.advance_to(end);
+        self.pool.run_until_stalled();
+    }
+
+    /// Runs all tasks until the future completes and advances the clock while it waits
+    pub fn block_on<F: Future + 'static>(&mut self, future: F) -> F::Output {
+        let (sender, mut receiver) = oneshot::channel();
+        self.shared.spawn(async move {
+            let _ = sender.send(future.await);
+        });
+
+        loop {
+            self.pool.run_until_stalled();
+            if let Ok(Some(output)) = receiver.try_recv() {
+                return output;
+            }
+            let deadline = self
+                .shared
+                .clock
+                .next_deadline()
+                .expect("The simulation stalled before the future completed");
+            self.shared.clock.advance_to(deadline);
+        }
+    }
+
+    /// Calls the control method on the node
+    pub fn call(&mut self, index: usize, method: &str, params: Value) -> SnekcloudResult<Value> {
+        let context = self.context(index);
+        let method = method.to_string();
+
+        self.block_on(async move { context.call_control_method(&method, params).await })
+    }
+}
+
+/// Derives the seed of the random generator of a link
+fn link_seed(seed: u64, from: usize, to: usize) -> u64 {
+    // the default hasher always starts with the same keys
+    let mut hasher = DefaultHasher::new();
+    (seed, from, to).hash(&mut hasher);
+
+    hasher.finish()
+}
@@ -6,10 +6,10 @@
 
 use crate::data::node_data::NodeData;
 use crate::utils::result::{SnekcloudError, SnekcloudResult};
-use crate::utils::settings::get_settings;
-use crate::utils::validate_node_id;
-use std::fs::create_dir;
-use std::path::{Path, PathBuf};
+use crate::utils::settings::Settings;
+use sha2::{Digest, Sha256};
+use std::fs::create_dir_all;
+use std::path::Path;
 use vented::server::data::Node;
 use vented::stream::{PublicKey, SecretKey};
 
@@ -19,34 +19,30 @@ const PRIVATE_KEY_FOOTER_LINE: &str = "\n---END-SNEKCLOUD-PRIVATE-KEY---";
 const PUBLIC_KEY_HEADER_LINE: &str = "---BEGIN-SNEKCLOUD-PUBLIC-KEY---\n";
 const PUBLIC_KEY_FOOTER_LINE: &str = "\n---END-SNEKCLOUD-PUBLIC-KEY---";
 
-/// Reads a folder of node public keys
-pub fn read_node_keys(path: &PathBuf) -> SnekcloudResult<Vec<Node>> {
+/// Reads the node data directory of the settings.
+/// The own node is skipped and nodes are trusted according to the settings.
+pub fn read_node_keys(settings: &Settings) -> SnekcloudResult<Vec<Node>> {
+    let path = &settings.node_data_dir;
     if !Path::new(path).exists() {
-        create_dir(path)?;
+        create_dir_all(path)?;
     }
-    let trusted_nodes = get_settings().trusted_nodes;
-    let own_id = get_settings().node_id;
-
-    let content = glob::glob(format!("{}/*.toml", path.to_string_lossy()).as_str())?
-        .filter_map(|path| {
-            let path = path.ok()?;
-            if path
-                .file_name()?
-                .to_string_lossy()
-                .eq_ignore_ascii_case("local")
-            {
-                return None;
+
+    let content = NodeData::read_dir(path)?
+        .into_iter()
+        .filter_map(|(path, data)| match data {
+            Ok(data) => Some(data),
+            Err(e) => {
+                log::warn!("Skipping invalid node file {:?}: {}", path, e);
+                None
             }
-            let data = NodeData::from_file(path).ok()?;
-
-            Some(Node {
-                public_key: data.public_key(),
-                addresses: data.addresses,
-                trusted: trusted_nodes.contains(&data.id),
-                id: data.id,
-            })
         })
-        .filter(|node| validate_node_id(&node.id) && node.id != own_id)
+        .filter(|data| data.id != settings.node_id)
+        .map(|data| Node {
+            public_key: data.public_key(),
+            addresses: data.addresses,
+            trusted: settings.trusted_nodes.contains(&data.id),
+            id: data.id,
+        })
         .collect();
 
     Ok(content)
@@ -70,7 +66,9 @@ pub fn extract_public_key(content: &str) -> SnekcloudResult<PublicKey> {
 
 /// Extracts a base64 encoded key between the prefix and suffix
 fn extract_key(content: &str, prefix: &str, suffix: &str) -> SnekcloudResult<[u8; 32]> {
-    if !content.starts_with(prefix) || !content.ends_with(suffix) {}
+    if !content.starts_with(prefix) || !content.ends_with(suffix) {
+        return Err(SnekcloudError::InvalidKey);
+    }
     let mut content = content.trim_start_matches(prefix);
     content = content.trim_end_matches(suffix);
 
@@ -110,6 +108,16 @@ fn armor_key(key: [u8; 32], prefix: &str, suffix: &str) -> String {
     format!("{}{}{}", prefix, base64::encode(key), suffix)
 }
 
+/// Returns a short printable fingerprint of the public key
+pub fn key_fingerprint(key: &PublicKey) -> String {
+    let hash = Sha256::digest(key.as_bytes());
+
+    format!(
+        "SHA256:{}",
+        base64::encode_config(hash, base64::STANDARD_NO_PAD)
+    )
+}
+
 /// Generates a new private key
 #[inline]
 pub fn generate_private_key() -> SecretKey {
@@ -4,29 +4,24 @@
  * See LICENSE for more information
  */
 
-use crate::utils::settings::get_settings;
 use chrono::Local;
 use colored::*;
 use log::{Level, LevelFilter};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::thread;
 
 /// Initializes the env_logger with a custom format
 /// that also logs the thread names
-pub fn init_logger() {
-    let log_dir = get_settings().log_folder;
+pub fn init_logger(log_dir: &Path) {
     if !log_dir.exists() {
-        fs::create_dir(&log_dir).expect("failed to create log dir");
+        fs::create_dir_all(log_dir).expect("failed to create log dir");
     }
     fern::Dispatch::new()
         .format(|out, message, record| {
             let color = get_level_style(record.level());
-            let mut thread_name = format!(
-                "thread::{}",
-                thread::current().name().unwrap_or("main").to_string()
-            );
+            let mut thread_name = format!("thread::{}", thread::current().name().unwrap_or("main"));
             thread_name.truncate(34);
             let mut target = record.target().to_string();
             target.truncate(39);
@@ -9,7 +9,7 @@ use rand::RngCore;
 use regex::Regex;
 use serde::Serialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::Path;
 
 pub mod keys;
 pub mod logging;
@@ -34,19 +34,38 @@ pub fn get_node_id() -> String {
 }
 
 /// Writes a pretty toml file to the given path
-pub fn write_toml_pretty<T: Serialize>(path: &PathBuf, value: &T) -> SnekcloudResult<()> {
+pub fn write_toml_pretty<T: Serialize>(path: &Path, value: &T) -> SnekcloudResult<()> {
+    let buf_str = to_toml_pretty(value)?;
+    write_atomic(path, buf_str.as_bytes())?;
+
+    Ok(())
+}
+
+/// Serializes the value to a pretty toml string
+pub fn to_toml_pretty<T: Serialize>(value: &T) -> SnekcloudResult<String> {
     let mut buf_str = String::new();
     let mut serializer = toml::Serializer::pretty(&mut buf_str);
     serializer.pretty_array(true);
     value.serialize(&mut serializer)?;
-    fs::write(path, buf_str.as_bytes())?;
 
-    Ok(())
+    Ok(buf_str)
 }
 
-pub fn write_json_pretty<T: Serialize>(path: &PathBuf, value: &T) -> SnekcloudResult<()> {
+pub fn write_json_pretty<T: Serialize>(path: &Path, value: &T) -> SnekcloudResult<()> {
     let string_value = serde_json::to_string_pretty(value)?;
-    fs::write(path, string_value.as_bytes())?;
+    write_atomic(path, string_value.as_bytes())?;
+
+    Ok(())
+}
+
+/// Writes the contents to a temporary file first and moves it to the given path
+/// so that the file is never left half written
+pub fn write_atomic(path: &Path, contents: &[u8]) -> SnekcloudResult<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)?;
 
     Ok(())
 }
@@ -4,9 +4,11 @@
  * See LICENSE for more information
  */
 
+use crate::utils::settings::SettingsIssue;
 use std::error::Error;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use vented::utils::result::VentedError;
 
 pub type SnekcloudResult<T> = Result<T, SnekcloudError>;
@@ -20,8 +22,23 @@ pub enum SnekcloudError {
     TomlSerializeError(toml::ser::Error),
     JsonError(serde_json::error::Error),
     InvalidKey,
+    InvalidNodeId(String),
+    LocalNode(String),
+    UnknownNode(String),
+    NodeConflict(String),
     ConfigError(config::ConfigError),
+    InvalidSettings(Vec<SettingsIssue>),
     GlobPatternError(glob::PatternError),
+    Timeout,
+    Cancelled,
+    Rpc(i64, String),
+    UnknownMethod(String),
+    ControlSocketDisabled,
+    ControlSocketInUse(PathBuf),
+    RemoteError(String),
+    SqliteError(rusqlite::Error),
+    InvalidNamespace(String),
+    InvalidTableName(String),
 }
 
 impl fmt::Display for SnekcloudError {
@@ -31,11 +48,34 @@ impl fmt::Display for SnekcloudError {
             Self::IoError(e) => write!(f, "IO Error: {}", e),
             Self::Base64DecodeError(e) => write!(f, "Base 64 Decode error: {}", e),
             Self::InvalidKey => write!(f, "Invalid Key!"),
+            Self::InvalidNodeId(id) => write!(f, "Invalid Node ID: {}", id),
+            Self::LocalNode(id) => write!(f, "Cannot add the local node {}", id),
+            Self::UnknownNode(id) => write!(f, "Unknown Node: {}", id),
+            Self::NodeConflict(id) => {
+                write!(f, "Node {} already exists with a different public key", id)
+            }
             Self::TomlDeserializeError(e) => write!(f, "Toml Deserialization Error: {}", e),
             Self::TomlSerializeError(e) => write!(f, "Toml Serialization Error: {}", e),
             Self::ConfigError(e) => write!(f, "Config Error: {}", e),
+            Self::InvalidSettings(issues) => write!(f, "{} invalid settings", issues.len()),
             Self::GlobPatternError(e) => write!(f, "Glob Error {}", e),
             Self::JsonError(e) => write!(f, "JSON Error: {}", e),
+            Self::Timeout => write!(f, "Timeout"),
+            Self::Cancelled => write!(f, "Cancelled"),
+            Self::Rpc(code, message) => write!(f, "RPC Error {}: {}", code, message),
+            Self::UnknownMethod(message) => write!(f, "{}", message),
+            Self::ControlSocketDisabled => write!(f, "The control socket is disabled"),
+            Self::ControlSocketInUse(path) => write!(
+                f,
+                "The control socket {:?} is used by a running instance",
+                path
+            ),
+            Self::RemoteError(message) => write!(f, "Remote Error: {}", message),
+            Self::SqliteError(e) => write!(f, "SQLite Error: {}", e),
+            Self::InvalidNamespace(namespace) => {
+                write!(f, "Invalid storage namespace: {}", namespace)
+            }
+            Self::InvalidTableName(name) => write!(f, "Invalid storage table name: {}", name),
         }
     }
 }
@@ -89,3 +129,9 @@ impl From<serde_json::error::Error> for SnekcloudError {
         Self::JsonError(error)
     }
 }
+
+impl From<rusqlite::Error> for SnekcloudError {
+    fn from(error: rusqlite::Error) -> Self {
+        Self::SqliteError(error)
+    }
+}
@@ -6,22 +6,59 @@
 
 use crate::modules::heartbeat::settings::HeartbeatSettings;
 use crate::modules::nodes_refresh::settings::NodesRefreshSettings;
-use crate::utils::result::SnekcloudResult;
+use crate::modules::registry::ModuleRegistry;
+use crate::utils::result::{SnekcloudError, SnekcloudResult};
 use crate::utils::{get_node_id, validate_node_id, write_toml_pretty};
-use config::File;
+use config::{File, Source, Value};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
+use std::net::ToSocketAddrs;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
 use vented::server::data::ServerTimeouts;
 
-const CONFIG_DIR: &str = "config/";
-const DEFAULT_CONFIG: &str = "config/00_default.toml";
-const GLOB_CONFIG: &str = "config/*.toml";
+pub const DEFAULT_CONFIG_DIR: &str = "config";
+pub const CONFIG_DIR_ENV: &str = "SNEKCLOUD_CONFIG_DIR";
+const DEFAULT_CONFIG: &str = "00_default.toml";
+const GLOB_CONFIG: &str = "*.toml";
+const TRUSTED_NODES_CONFIG: &str = "99_trusted_nodes.toml";
 const ENV_PREFIX: &str = "SNEKCLOUD";
 
 pub trait ValidateSettings {
-    fn validate(&self);
+    /// Returns all problems found in the settings
+    fn validate(&self) -> Vec<SettingsIssue>;
+}
+
+/// A single invalid value found when validating the settings
+#[derive(Clone, Debug)]
+pub struct SettingsIssue {
+    pub key: String,
+    pub message: String,
+}
+
+impl SettingsIssue {
+    pub fn new<S1: ToString, S2: ToString>(key: S1, message: S2) -> Self {
+        Self {
+            key: key.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    /// Prefixes the key of the issue with the key of the parent table
+    pub fn prefixed(self, prefix: &str) -> Self {
+        Self {
+            key: format!("{}.{}", prefix, self.key),
+            message: self.message,
+        }
+    }
+}
+
+impl fmt::Display for SettingsIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -33,15 +70,35 @@ pub struct Settings {
     pub trusted_nodes: Vec<String>,
     pub send_timeout_secs: u64,
     pub redirect_timeout_secs: u64,
+    pub shutdown_timeout_secs: u64,
     pub log_folder: PathBuf,
-    // modules need to be last because it's a table
+    pub control_socket: Option<PathBuf>,
+    /// The address the prometheus metrics are served on
+    pub metrics_address: Option<String>,
+    /// The address the read-only json api is served on
+    pub api_address: Option<String>,
+    pub database_path: PathBuf,
+    /// The directory the settings were loaded from.
+    /// Relative paths in the settings are resolved against this directory.
+    #[serde(skip)]
+    pub config_dir: PathBuf,
+    /// Relative paths that were found in the working directory instead of the config directory
+    #[serde(skip)]
+    pub legacy_paths: Vec<PathBuf>,
+    // tables need to be last
+    pub acl: AclSettings,
+    pub limits: LimitSettings,
     pub modules: ModuleSettings,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ModuleSettings {
     pub heartbeat: HeartbeatSettings,
     pub nodes_refresh: NodesRefreshSettings,
+    /// Tables of modules without typed settings.
+    /// Tables that don't belong to a module of the registry are reported when validating
+    #[serde(flatten, skip_serializing)]
+    pub other: HashMap<String, serde_json::Value>,
 }
 
 impl Default for Settings {
@@ -52,15 +109,56 @@ impl Default for Settings {
             private_key: PathBuf::from("private_key"),
             node_data_dir: PathBuf::from("nodes"),
             log_folder: PathBuf::from("logs"),
+            control_socket: Some(PathBuf::from("snekcloud.sock")),
+            metrics_address: None,
+            api_address: None,
+            database_path: PathBuf::from("snekcloud.db"),
             trusted_nodes: vec![],
             send_timeout_secs: 5,
             redirect_timeout_secs: 20,
+            shutdown_timeout_secs: 10,
+            config_dir: PathBuf::from(DEFAULT_CONFIG_DIR),
+            legacy_paths: vec![],
+            acl: AclSettings::default(),
+            limits: LimitSettings::default(),
             modules: ModuleSettings::default(),
         }
     }
 }
 
 impl Settings {
+    /// Resolves all relative paths against the config directory
+    fn resolve_paths(&mut self) {
+        self.private_key = self.resolve_legacy_path(self.private_key.clone());
+        self.node_data_dir = self.resolve_legacy_path(self.node_data_dir.clone());
+        self.log_folder = self.resolve_legacy_path(self.log_folder.clone());
+        self.database_path = resolve_path(&self.config_dir, &self.database_path);
+        if let Some(control_socket) = &self.control_socket {
+            self.control_socket = Some(resolve_path(&self.config_dir, control_socket));
+        }
+        if let Some(output_file) = &self.modules.heartbeat.output_file {
+            self.modules.heartbeat.output_file = Some(resolve_path(&self.config_dir, output_file));
+        }
+    }
+
+    /// Resolves a path that was relative to the working directory before the
+    /// config directory existed. If the path only exists in the working directory
+    /// it is kept so that existing nodes don't lose their key and nodes.
+    fn resolve_legacy_path(&mut self, path: PathBuf) -> PathBuf {
+        let resolved = resolve_path(&self.config_dir, &path);
+
+        if path.is_relative() && !resolved.exists() && path.exists() {
+            self.legacy_paths.push(path.clone());
+            path
+        } else {
+            resolved
+        }
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
     pub fn timeouts(&self) -> ServerTimeouts {
         ServerTimeouts {
             redirect_timeout: Duration::from_secs(self.redirect_timeout_secs),
@@ -69,63 +167,573 @@ impl Settings {
     }
 }
 
-impl Default for ModuleSettings {
-    fn default() -> Self {
-        Self {
-            heartbeat: HeartbeatSettings::default(),
-            nodes_refresh: NodesRefreshSettings::default(),
+impl Settings {
+    /// Restores the values of all settings that can't be changed while the server is running
+    /// and returns the keys of the settings that were changed anyway
+    pub fn reject_immutable_changes(&mut self, current: &Settings) -> Vec<&'static str> {
+        let mut rejected = Vec::new();
+
+        macro_rules! keep_current {
+            ($key:expr, $($field:ident).+) => {
+                if self.$($field).+ != current.$($field).+ {
+                    rejected.push($key);
+                    self.$($field).+ = current.$($field).+.clone();
+                }
+            };
         }
+        keep_current!("listen_addresses", listen_addresses);
+        keep_current!("node_id", node_id);
+        keep_current!("private_key", private_key);
+        keep_current!("node_data_dir", node_data_dir);
+        keep_current!("send_timeout_secs", send_timeout_secs);
+        keep_current!("redirect_timeout_secs", redirect_timeout_secs);
+        keep_current!("shutdown_timeout_secs", shutdown_timeout_secs);
+        keep_current!("log_folder", log_folder);
+        keep_current!("control_socket", control_socket);
+        keep_current!("metrics_address", metrics_address);
+        keep_current!("api_address", api_address);
+        keep_current!("database_path", database_path);
+        keep_current!("modules.heartbeat.enabled", modules.heartbeat.enabled);
+        keep_current!(
+            "modules.nodes_refresh.enabled",
+            modules.nodes_refresh.enabled
+        );
+
+        rejected
+    }
+
+    /// Validates the settings and returns an error if any value is invalid.
+    /// All issues are logged as errors.
+    pub fn check(&self) -> SnekcloudResult<()> {
+        let issues = self.validate();
+        if issues.is_empty() {
+            return Ok(());
+        }
+        for issue in &issues {
+            log::error!("Invalid setting {}", issue);
+        }
+
+        Err(SnekcloudError::InvalidSettings(issues))
     }
 }
 
 impl ValidateSettings for Settings {
-    fn validate(&self) {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+
         if !self.private_key.exists() {
-            panic!(format!("Private key {:?} does not exist", self.private_key));
+            issues.push(SettingsIssue::new(
+                "private_key",
+                format!("Private key {:?} does not exist", self.private_key),
+            ));
         }
         if self.send_timeout_secs == 0 {
-            panic!("Send timeout must be greater than 0");
+            issues.push(SettingsIssue::new(
+                "send_timeout_secs",
+                "Send timeout must be greater than 0",
+            ));
         }
         if self.redirect_timeout_secs == 0 {
-            panic!("Redirect timeout must be greater than 0");
+            issues.push(SettingsIssue::new(
+                "redirect_timeout_secs",
+                "Redirect timeout must be greater than 0",
+            ));
         }
         if !validate_node_id(&self.node_id) {
-            panic!(format!("Invalid NodeID {}", self.node_id));
+            issues.push(SettingsIssue::new(
+                "node_id",
+                format!("Invalid NodeID {}", self.node_id),
+            ));
+        }
+        for address in &self.listen_addresses {
+            if address.to_socket_addrs().is_err() {
+                issues.push(SettingsIssue::new(
+                    "listen_addresses",
+                    format!("Invalid listen address {}", address),
+                ));
+            }
+        }
+        if let Some(address) = &self.metrics_address {
+            if address.to_socket_addrs().is_err() {
+                issues.push(SettingsIssue::new(
+                    "metrics_address",
+                    format!("Invalid metrics address {}", address),
+                ));
+            }
         }
-        self.modules.validate();
+        if let Some(address) = &self.api_address {
+            if address.to_socket_addrs().is_err() {
+                issues.push(SettingsIssue::new(
+                    "api_address",
+                    format!("Invalid api address {}", address),
+                ));
+            }
+        }
+        issues.extend(
+            self.acl
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("acl")),
+        );
+        issues.extend(
+            self.limits
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("limits")),
+        );
+        issues.extend(
+            self.modules
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("modules")),
+        );
+
+        issues
     }
 }
 
 impl ValidateSettings for ModuleSettings {
-    fn validate(&self) {}
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+        issues.extend(
+            self.heartbeat
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("heartbeat")),
+        );
+        issues.extend(
+            self.nodes_refresh
+                .validate()
+                .into_iter()
+                .map(|issue| issue.prefixed("nodes_refresh")),
+        );
+        let modules: Vec<&str> = ModuleRegistry::default().names().collect();
+        let mut unknown: Vec<&String> = self
+            .other
+            .keys()
+            .filter(|name| !modules.contains(&name.as_str()))
+            .collect();
+        unknown.sort();
+        for name in unknown {
+            issues.push(SettingsIssue::new(
+                name,
+                format!(
+                    "Unknown module {}. Known modules are {}",
+                    name,
+                    modules.join(", ")
+                ),
+            ));
+        }
+
+        issues
+    }
+}
+
+/// When a module should be restarted after its run loop stopped
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    /// Returns if a module that stopped with the given outcome should be restarted
+    pub fn should_restart(&self, failed: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnFailure => failed,
+            Self::Always => true,
+        }
+    }
+}
+
+/// Controls how the supervisor restarts a module
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RestartSettings {
+    pub policy: RestartPolicy,
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// The maximum number of restarts within the restart window
+    /// before the module is considered failed
+    pub max_restarts: usize,
+    pub restart_window_secs: u64,
+}
+
+impl Default for RestartSettings {
+    fn default() -> Self {
+        Self {
+            policy: RestartPolicy::OnFailure,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 60000,
+            max_restarts: 5,
+            restart_window_secs: 300,
+        }
+    }
+}
+
+impl RestartSettings {
+    pub fn initial_backoff(&self) -> Duration {
+        Duration::from_millis(self.initial_backoff_ms)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+
+    pub fn restart_window(&self) -> Duration {
+        Duration::from_secs(self.restart_window_secs)
+    }
+}
+
+impl ValidateSettings for RestartSettings {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+
+        if self.initial_backoff_ms == 0 {
+            issues.push(SettingsIssue::new(
+                "initial_backoff_ms",
+                "Initial backoff must be greater than 0",
+            ));
+        }
+        if self.max_backoff_ms < self.initial_backoff_ms {
+            issues.push(SettingsIssue::new(
+                "max_backoff_ms",
+                "Max backoff must not be smaller than the initial backoff",
+            ));
+        }
+        if self.restart_window_secs == 0 {
+            issues.push(SettingsIssue::new(
+                "restart_window_secs",
+                "Restart window must be greater than 0",
+            ));
+        }
+
+        issues
+    }
+}
+
+/// Which nodes may send an event
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AclPolicy {
+    /// Every node with a known key
+    Open,
+    /// Only the nodes in `trusted_nodes`
+    Trusted,
 }
 
-/// Returns the settings that are lazily retrieved at runtime
-pub fn get_settings() -> Settings {
-    lazy_static! {
-        static ref SETTINGS: Settings = load_settings().expect("Failed to get settings");
+/// Controls which nodes may send which events
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AclSettings {
+    /// The policy of events that don't match any rule
+    pub default: AclPolicy,
+    /// The policies by event name.
+    /// Names ending with `*` match every event starting with the part before it.
+    pub events: BTreeMap<String, AclPolicy>,
+}
+
+impl Default for AclSettings {
+    fn default() -> Self {
+        Self {
+            default: AclPolicy::Open,
+            events: BTreeMap::new(),
+        }
+    }
+}
+
+impl AclSettings {
+    /// Returns the policy of the rule with the most specific pattern matching the event
+    pub fn policy(&self, event_name: &str) -> AclPolicy {
+        match_event(&self.events, event_name)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+impl ValidateSettings for AclSettings {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        validate_event_patterns("events", &self.events)
+    }
+}
+
+/// Limits for the events each node may send
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LimitSettings {
+    /// The number of events a node may send per second on average
+    pub events_per_second: f64,
+    /// The number of events a node may send at once after being idle
+    pub burst: u32,
+    /// The maximum payload size of events without a more specific limit.
+    /// It is checked after the event was decoded.
+    pub max_payload_bytes: usize,
+    /// The maximum payload sizes by event name or pattern
+    pub max_payload_bytes_per_event: BTreeMap<String, usize>,
+}
+
+impl Default for LimitSettings {
+    fn default() -> Self {
+        Self {
+            events_per_second: 50.0,
+            burst: 100,
+            max_payload_bytes: 1024 * 1024,
+            max_payload_bytes_per_event: BTreeMap::new(),
+        }
     }
+}
 
-    SETTINGS.clone()
+impl LimitSettings {
+    /// Returns the maximum payload size for the event
+    pub fn payload_limit(&self, event_name: &str) -> usize {
+        match_event(&self.max_payload_bytes_per_event, event_name)
+            .copied()
+            .unwrap_or(self.max_payload_bytes)
+    }
 }
 
-fn load_settings() -> SnekcloudResult<Settings> {
-    if !Path::new(CONFIG_DIR).exists() {
-        fs::create_dir(CONFIG_DIR)?;
+impl ValidateSettings for LimitSettings {
+    fn validate(&self) -> Vec<SettingsIssue> {
+        let mut issues = Vec::new();
+
+        if !self.events_per_second.is_finite() || self.events_per_second <= 0.0 {
+            issues.push(SettingsIssue::new(
+                "events_per_second",
+                "Events per second must be greater than 0",
+            ));
+        }
+        if self.burst == 0 {
+            issues.push(SettingsIssue::new("burst", "Burst must be greater than 0"));
+        }
+        issues.extend(validate_event_patterns(
+            "max_payload_bytes_per_event",
+            &self.max_payload_bytes_per_event,
+        ));
+
+        issues
     }
-    write_toml_pretty(&PathBuf::from(DEFAULT_CONFIG), &Settings::default())?;
+}
+
+/// Returns the value of the exact event name or the longest pattern matching the event.
+/// Patterns end with `*` and match every event starting with the part before it.
+fn match_event<'a, T>(rules: &'a BTreeMap<String, T>, event_name: &str) -> Option<&'a T> {
+    if let Some(value) = rules.get(event_name) {
+        return Some(value);
+    }
+    rules
+        .iter()
+        .filter_map(|(pattern, value)| {
+            let prefix = pattern.strip_suffix('*')?;
+            if event_name.starts_with(prefix) {
+                Some((prefix.len(), value))
+            } else {
+                None
+            }
+        })
+        .max_by_key(|(length, _)| *length)
+        .map(|(_, value)| value)
+}
+
+fn validate_event_patterns<T>(key: &str, rules: &BTreeMap<String, T>) -> Vec<SettingsIssue> {
+    rules
+        .keys()
+        .filter(|pattern| {
+            pattern.is_empty() || pattern.strip_suffix('*').unwrap_or(pattern).contains('*')
+        })
+        .map(|pattern| {
+            SettingsIssue::new(
+                key,
+                format!(
+                    "Invalid event pattern {:?}. Only a trailing * is allowed",
+                    pattern
+                ),
+            )
+        })
+        .collect()
+}
+
+/// The source a configuration value was loaded from
+#[derive(Clone, Debug)]
+pub enum SettingsSource {
+    Default,
+    File(PathBuf),
+    Environment(String),
+}
+
+impl fmt::Display for SettingsSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::File(path) => write!(f, "file {}", path.to_string_lossy()),
+            Self::Environment(var) => write!(f, "env {}", var),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrustedNodesOverride {
+    trusted_nodes: Vec<String>,
+}
+
+/// Writes the list of trusted nodes to the override file in the config directory.
+/// The file is loaded last so it replaces the trusted nodes of all other config files.
+pub fn write_trusted_nodes(
+    config_dir: &Path,
+    trusted_nodes: Vec<String>,
+) -> SnekcloudResult<PathBuf> {
+    let path = config_dir.join(TRUSTED_NODES_CONFIG);
+    write_toml_pretty(&path, &TrustedNodesOverride { trusted_nodes })?;
+
+    Ok(path)
+}
+
+/// Loads the settings from the given config directory
+pub fn load_settings(config_dir: &Path) -> SnekcloudResult<Settings> {
+    if !config_dir.exists() {
+        fs::create_dir_all(config_dir)?;
+    }
+    let default_config = config_dir.join(DEFAULT_CONFIG);
+    write_toml_pretty(&default_config, &Settings::default())?;
 
     let mut settings = config::Config::default();
     settings
-        .merge(config::File::with_name(DEFAULT_CONFIG))?
+        .merge(File::from(default_config))?
         .merge(
-            glob::glob(GLOB_CONFIG)?
-                .map(|path| File::from(path.unwrap()))
+            glob_config_files(config_dir)?
+                .into_iter()
+                .map(File::from)
                 .collect::<Vec<_>>(),
         )?
         .merge(config::Environment::with_prefix(ENV_PREFIX))?;
 
-    let settings: Settings = settings.try_into()?;
+    let mut settings: Settings = settings.try_into()?;
+    settings.config_dir = config_dir.to_path_buf();
+    settings.resolve_paths();
 
     Ok(settings)
 }
+
+/// Returns all config files of the directory in the order they're loaded
+fn glob_config_files(config_dir: &Path) -> SnekcloudResult<Vec<PathBuf>> {
+    let pattern = config_dir.join(GLOB_CONFIG);
+    let files = glob::glob(&pattern.to_string_lossy())?
+        .filter_map(|path| path.ok())
+        .collect();
+
+    Ok(files)
+}
+
+/// Resolves a relative path against the given base directory
+fn resolve_path(base: &Path, path: &Path) -> PathBuf {
+    if path.is_relative() {
+        base.join(path)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Returns the source of every configuration key that has been set.
+/// Sources are applied in the same order as when loading the settings
+/// so the last source that sets a key is the one that supplied the value.
+pub fn load_settings_sources(
+    config_dir: &Path,
+) -> SnekcloudResult<BTreeMap<String, SettingsSource>> {
+    let mut sources = BTreeMap::new();
+    let default_path = config_dir.join(DEFAULT_CONFIG);
+
+    for path in glob_config_files(config_dir)? {
+        let source = if path == default_path {
+            SettingsSource::Default
+        } else {
+            SettingsSource::File(path.clone())
+        };
+        for key in flatten_keys(File::from(path).collect()?) {
+            sources.insert(key, source.clone());
+        }
+    }
+    for key in flatten_keys(config::Environment::with_prefix(ENV_PREFIX).collect()?) {
+        let var = format!("{}_{}", ENV_PREFIX, key.to_uppercase());
+        sources.insert(key, SettingsSource::Environment(var));
+    }
+
+    Ok(sources)
+}
+
+/// Returns the dotted keys of all values in the given table
+fn flatten_keys(table: HashMap<String, Value>) -> Vec<String> {
+    table
+        .into_iter()
+        .flat_map(|(key, value)| match value.into_table() {
+            Ok(table) => flatten_keys(table)
+                .into_iter()
+                .map(|child| format!("{}.{}", key, child))
+                .collect(),
+            Err(_) => vec![key],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_prefers_exact_names_and_longer_patterns() {
+        let mut settings = AclSettings {
+            default: AclPolicy::Open,
+            ..AclSettings::default()
+        };
+        settings
+            .events
+            .insert("admin:*".to_string(), AclPolicy::Trusted);
+        settings
+            .events
+            .insert("admin:status:*".to_string(), AclPolicy::Open);
+        settings
+            .events
+            .insert("admin:status:reset".to_string(), AclPolicy::Trusted);
+
+        assert_eq!(settings.policy("admin:restart"), AclPolicy::Trusted);
+        assert_eq!(settings.policy("admin:status:get"), AclPolicy::Open);
+        assert_eq!(settings.policy("admin:status:reset"), AclPolicy::Trusted);
+        assert_eq!(settings.policy("heartbeat:echo"), AclPolicy::Open);
+    }
+
+    #[test]
+    fn it_uses_the_default_policy_without_rules() {
+        let settings = AclSettings {
+            default: AclPolicy::Trusted,
+            ..AclSettings::default()
+        };
+
+        assert_eq!(settings.policy("admin:restart"), AclPolicy::Trusted);
+    }
+
+    #[test]
+    fn it_uses_the_most_specific_payload_limit() {
+        let mut settings = LimitSettings {
+            max_payload_bytes: 100,
+            ..LimitSettings::default()
+        };
+        let limits = &mut settings.max_payload_bytes_per_event;
+        limits.insert("nodes:*".to_string(), 200);
+        limits.insert("nodes:list*".to_string(), 300);
+        limits.insert("nodes:list".to_string(), 400);
+
+        assert_eq!(settings.payload_limit("nodes:list"), 400);
+        assert_eq!(settings.payload_limit("nodes:list_request"), 300);
+        assert_eq!(settings.payload_limit("nodes:other"), 200);
+        assert_eq!(settings.payload_limit("heartbeat:echo"), 100);
+    }
+
+    #[test]
+    fn it_only_matches_patterns_ending_with_a_wildcard() {
+        let mut rules = BTreeMap::new();
+        rules.insert("nodes".to_string(), 1);
+        rules.insert("*".to_string(), 2);
+
+        assert_eq!(match_event(&rules, "nodes"), Some(&1));
+        assert_eq!(match_event(&rules, "nodes:list"), Some(&2));
+        assert_eq!(match_event(&BTreeMap::<String, u8>::new(), "nodes"), None);
+    }
+}